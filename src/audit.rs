@@ -0,0 +1,79 @@
+//! A durable record of every handled command, appended to a file as one JSON object per line.
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use clock::Timestamp;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AuditEntry<'a> {
+    pub ts: Timestamp,
+    pub team: &'a str,
+    pub channel: &'a str,
+    pub token: &'a str,
+    pub user_id: &'a str,
+    pub command: &'a str,
+    /// `ok`, `refused` when the command was understood but not allowed, or the error
+    pub outcome: &'a str,
+}
+
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open the log for appending, creating it if needed
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    /// Append the entry as a line, flushed straight away so it survives a crash
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        line.push('\n');
+        let mut file = self.file.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "audit log poisoned"))?;
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+    use std::io::Read;
+
+    #[test]
+    fn test_record_appends() {
+        let path = env::temp_dir().join("slack-token-test-record-appends.jsonl");
+        fs::File::create(&path).unwrap().write_all(b"{\"existing\": true}\n").unwrap();
+        let entry = AuditEntry {
+            ts: 1500000000,
+            team: "T1",
+            channel: "C1",
+            token: "default",
+            user_id: "U1",
+            command: "get",
+            outcome: "ok",
+        };
+        {
+            let log = AuditLog::open(&path).unwrap();
+            log.record(&entry).unwrap();
+            log.record(&AuditEntry { command: "drop", ..entry }).unwrap();
+        }
+
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let _ = fs::remove_file(&path);
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "{\"existing\": true}");
+        assert_eq!(lines[1],
+                   "{\"ts\":1500000000,\"team\":\"T1\",\"channel\":\"C1\",\"token\":\"default\",\
+                    \"user_id\":\"U1\",\"command\":\"get\",\"outcome\":\"ok\"}");
+        assert!(lines[2].contains("\"command\":\"drop\""));
+    }
+}
@@ -0,0 +1,60 @@
+//! Wall-clock helpers. Times are stored as whole seconds since the Unix epoch so that they can be
+//! compared, serialized, and injected in tests without any ceremony.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub type Timestamp = u64;
+
+pub fn now() -> Timestamp {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Format a number of seconds as a short human readable duration, eg "1h 5m"
+pub fn format_duration(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Parse a duration like "90", "45s", "30m" or "2h" into seconds. Bare numbers are seconds.
+pub fn parse_duration(text: &str) -> Option<u64> {
+    let (number, unit) = match text.find(|c: char| !c.is_digit(10)) {
+        Some(at) => text.split_at(at),
+        None => (text, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(30), "30s");
+        assert_eq!(format_duration(12 * 60 + 5), "12m");
+        assert_eq!(format_duration(3600 + 5 * 60), "1h 5m");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("45s"), Some(45));
+        assert_eq!(parse_duration("30m"), Some(30 * 60));
+        assert_eq!(parse_duration("2h"), Some(2 * 3600));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("5d"), None);
+        assert_eq!(parse_duration("5m30s"), None);
+    }
+}
@@ -1,28 +1,549 @@
+use std::cmp;
 use std::str::FromStr;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Commands {
+    #[serde(rename = "list")]
     List,
+    #[serde(rename = "get")]
     Get,
+    #[serde(rename = "drop")]
     Drop,
+    #[serde(rename = "afteryou")]
     AfterYou,
+    #[serde(rename = "barge")]
     Barge,
+    #[serde(rename = "steal")]
     Steal,
+    #[serde(rename = "top")]
+    Top,
+    #[serde(rename = "export")]
+    Export,
+    #[serde(rename = "import")]
+    Import,
+    #[serde(rename = "whois")]
+    Whois,
+    #[serde(rename = "delete")]
+    Delete,
+    #[serde(rename = "ping")]
+    Ping,
+    #[serde(rename = "rename")]
+    Rename,
+    #[serde(rename = "backup")]
+    Backup,
+    #[serde(rename = "help")]
+    Help,
+    #[serde(rename = "announce")]
+    Announce,
+    #[serde(rename = "set")]
+    Set,
+    #[serde(rename = "freeze")]
+    Freeze,
+    #[serde(rename = "unfreeze")]
+    Unfreeze,
+    #[serde(rename = "swap")]
+    Swap,
+    #[serde(rename = "position")]
+    Position,
+    #[serde(rename = "config")]
+    Config,
+    #[serde(rename = "swap-next")]
+    SwapNext,
+    #[serde(rename = "purge")]
+    Purge,
+    #[serde(rename = "describe")]
+    Describe,
+    #[serde(rename = "stats")]
+    Stats,
+    #[serde(rename = "holdtime")]
+    Holdtime,
+    #[serde(rename = "watch")]
+    Watch,
+    #[serde(rename = "unwatch")]
+    Unwatch,
+    #[serde(rename = "auto-drop")]
+    AutoDrop,
+    #[serde(rename = "requeue")]
+    Requeue,
 }
 
 use super::Commands::*;
 
+/// Everything there is to know about a command, for parsing it and explaining it
+pub struct CommandInfo {
+    pub command: Commands,
+    /// The name the command is typed as
+    pub name: &'static str,
+    pub description: &'static str,
+    pub example: &'static str,
+    /// Only admins may run the command, so it's left out of everyone else's help
+    pub admin_only: bool,
+}
+
+const COMMANDS: &'static [CommandInfo] = &[CommandInfo {
+                                               command: List,
+                                               name: "list",
+                                               description: "shows everyone in the queue, `--mentions off` to list names \
+                                                             without notifying anyone or `--sort name` to sort them",
+                                               example: "/token list --sort name",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Get,
+                                               name: "get",
+                                               description: "adds yourself to the queue. With `--sticky` you rejoin \
+                                                             whenever you release the token, and with \
+                                                             `--notify-on-front` you're mentioned when it's your turn",
+                                               example: "/token get --sticky",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Drop,
+                                               name: "drop",
+                                               description: "removes yourself from the queue, releasing the token if you hold it",
+                                               example: "/token drop",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: AfterYou,
+                                               name: "afteryou",
+                                               description: "lets the people behind you go first",
+                                               example: "/token afteryou 2",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Requeue,
+                                               name: "requeue",
+                                               description: "passes the token on and sends you to the back of the queue, \
+                                                             unlike `afteryou` which only steps back",
+                                               example: "/token requeue",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Barge,
+                                               name: "barge",
+                                               description: "jumps to the front of the people waiting",
+                                               example: "/token barge",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Steal,
+                                               name: "steal",
+                                               description: "takes the token from whoever is holding it",
+                                               example: "/token steal fixing prod outage",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: SwapNext,
+                                               name: "swap-next",
+                                               description: "lets the holder choose who gets the token after them",
+                                               example: "/token swap-next @alice",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Top,
+                                               name: "top",
+                                               description: "shows who is holding the token",
+                                               example: "/token top",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Position,
+                                               name: "position",
+                                               description: "shows where you are in the queue, and roughly how long until \
+                                                             your turn",
+                                               example: "/token position",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Export,
+                                               name: "export",
+                                               description: "exports the queue as JSON",
+                                               example: "/token export json",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Import,
+                                               name: "import",
+                                               description: "replaces the queue with one exported as JSON (admins only)",
+                                               example: "/token import {\"users\": []}",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Whois,
+                                               name: "whois",
+                                               description: "shows where someone is in the queue",
+                                               example: "/token whois @alice",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Delete,
+                                               name: "delete",
+                                               description: "deletes a named token (admins only)",
+                                               example: "/token delete demo",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Ping,
+                                               name: "ping",
+                                               description: "checks the bot is working",
+                                               example: "/token ping",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Rename,
+                                               name: "rename",
+                                               description: "renames a named token (admins only)",
+                                               example: "/token rename demo demo-laptop",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Backup,
+                                               name: "backup",
+                                               description: "exports all of this channel's tokens as JSON",
+                                               example: "/token backup",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Holdtime,
+                                               name: "holdtime",
+                                               description: "shows who has held the token for longest in total",
+                                               example: "/token holdtime",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Watch,
+                                               name: "watch",
+                                               description: "posts a message which keeps showing the queue as it changes",
+                                               example: "/token watch",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Unwatch,
+                                               name: "unwatch",
+                                               description: "stops updating the message from `watch`",
+                                               example: "/token unwatch",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: AutoDrop,
+                                               name: "auto-drop",
+                                               description: "drops you automatically once you've held the token for as long \
+                                                             as you say",
+                                               example: "/token auto-drop on 30m",
+                                               admin_only: false,
+                                           },
+                                           CommandInfo {
+                                               command: Announce,
+                                               name: "announce",
+                                               description: "posts a notice to everyone in the queue (admins only)",
+                                               example: "/token announce prod is down for 30m, hold your spots",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Set,
+                                               name: "set",
+                                               description: "changes a setting of the queue, eg its maximum size or where \
+                                                             priority users join (admins only)",
+                                               example: "/token set maxsize 10",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Freeze,
+                                               name: "freeze",
+                                               description: "stops anyone joining, leaving or reordering the queue (admins only)",
+                                               example: "/token freeze",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Unfreeze,
+                                               name: "unfreeze",
+                                               description: "lets the queue change again after a freeze (admins only)",
+                                               example: "/token unfreeze",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Swap,
+                                               name: "swap",
+                                               description: "swaps the people at two positions in the queue (admins only)",
+                                               example: "/token swap 2 3",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Describe,
+                                               name: "describe",
+                                               description: "sets what a token is for, shown when listing it (admins only)",
+                                               example: "/token describe prod prod deploy lock",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Config,
+                                               name: "config",
+                                               description: "shows the settings in effect in this channel (admins only)",
+                                               example: "/token config",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Purge,
+                                               name: "purge",
+                                               description: "removes empty queues and expires stale holders in every channel \
+                                                             (super admins only)",
+                                               example: "/token purge",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Stats,
+                                               name: "stats",
+                                               description: "gives an overview of every queue (admins only)",
+                                               example: "/token stats global",
+                                               admin_only: true,
+                                           },
+                                           CommandInfo {
+                                               command: Help,
+                                               name: "help",
+                                               description: "explains every command, or just one, or walks through `examples`",
+                                               example: "/token help steal",
+                                               admin_only: false,
+                                           }];
+
+/// One step of a typical session, as shown by `/token help examples`
+pub struct Example {
+    pub command: Commands,
+    /// What is typed
+    pub typed: &'static str,
+    /// What it does, and why you might type it
+    pub note: &'static str,
+}
+
+const EXAMPLES: &'static [Example] = &[Example {
+                                           command: Get,
+                                           typed: "/token get",
+                                           note: "join the queue, or take the token straight away if no one has it",
+                                       },
+                                       Example {
+                                           command: List,
+                                           typed: "/token list",
+                                           note: "see who has the token and who is waiting",
+                                       },
+                                       Example {
+                                           command: Drop,
+                                           typed: "/token drop",
+                                           note: "finished? hand the token to whoever is next",
+                                       },
+                                       Example {
+                                           command: Steal,
+                                           typed: "/token steal fixing prod outage",
+                                           note: "in an emergency take the token from whoever has it, saying why",
+                                       }];
+
 impl FromStr for Commands {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "list" => Ok(List),
-            "get" => Ok(Get),
-            "drop" => Ok(Drop),
-            "afteryou" => Ok(AfterYou),
-            "barge" => Ok(Barge),
-            "steal" => Ok(Steal),
-            _ => Err("invalid command"),
+        let s = s.to_lowercase();
+        COMMANDS.iter()
+            .find(|info| info.name == s)
+            .map(|info| info.command)
+            .ok_or("invalid command")
+    }
+}
+
+impl Commands {
+    /// Every command, in the order they are listed in help
+    pub fn all() -> &'static [CommandInfo] {
+        COMMANDS
+    }
+
+    /// A short session showing the commands people use most, in the order they'd use them
+    pub fn examples() -> &'static [Example] {
+        EXAMPLES
+    }
+
+    pub fn info(&self) -> &'static CommandInfo {
+        // Every command is in the table
+        COMMANDS.iter().find(|info| info.command == *self).unwrap()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.info().name
+    }
+
+    /// Whether the command can change a token's queue
+    pub fn mutates(&self) -> bool {
+        match *self {
+            Get | Drop | AfterYou | Requeue | Barge | Steal | Import | Swap | SwapNext => true,
+            _ => false,
+        }
+    }
+
+    /// Only super admins may run the command, so it's left out of every other admin's help too
+    pub fn super_admin_only(&self) -> bool {
+        *self == Purge
+    }
+
+    /// Parse a command, also accepting any unambiguous abbreviation, and `+`/`-` for `get`/`drop`,
+    /// unless `strict` is set
+    pub fn parse(s: &str, strict: bool) -> Option<Commands> {
+        if let Ok(command) = s.parse() {
+            return Some(command);
+        }
+        if strict || s.is_empty() {
+            return None;
+        }
+        match s {
+            "+" => return Some(Get),
+            "-" => return Some(Drop),
+            _ => {}
+        }
+        let s = s.to_lowercase();
+        let mut matches = COMMANDS.iter().filter(|info| info.name.starts_with(s.as_str()));
+        match (matches.next(), matches.next()) {
+            (Some(info), None) => Some(info.command),
+            _ => None,
+        }
+    }
+
+    /// Guess which command was meant by a mistyped one
+    pub fn suggest(s: &str) -> Option<Commands> {
+        let s = s.to_lowercase();
+        COMMANDS.iter()
+            .map(|info| (edit_distance(&s, info.name), info.command))
+            .filter(|&(distance, _)| distance <= 2)
+            .min_by_key(|&(distance, _)| distance)
+            .map(|(_, command)| command)
+    }
+}
+
+/// A slash command's text, of the form `[token name] <command> [options]`
+pub struct CommandText<'a> {
+    pub token_name: Option<&'a str>,
+    /// The command as it was typed
+    pub command_name: &'a str,
+    pub command: Option<Commands>,
+    pub options: Option<&'a str>,
+}
+
+impl<'a> CommandText<'a> {
+    pub fn parse(text: &'a str, strict: bool) -> CommandText<'a> {
+        let mut parts = text.trim().splitn(2, ' ');
+        let first = parts.next().unwrap_or("");
+        let rest = parts.next().map(|s| s.trim());
+        if let Some(command) = Commands::parse(first, strict) {
+            return CommandText {
+                token_name: None,
+                command_name: first,
+                command: Some(command),
+                options: rest,
+            };
+        }
+        // Otherwise the first word may be the name of the token to act on
+        if let Some(rest) = rest {
+            let mut parts = rest.splitn(2, ' ');
+            let second = parts.next().unwrap_or("");
+            if let Some(command) = Commands::parse(second, strict) {
+                return CommandText {
+                    token_name: Some(first),
+                    command_name: second,
+                    command: Some(command),
+                    options: parts.next().map(|s| s.trim()),
+                };
+            }
+        }
+        CommandText {
+            token_name: None,
+            command_name: first,
+            command: None,
+            options: rest,
+        }
+    }
+}
+
+/// The Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            let cost = cmp::min(substitution, cmp::min(previous[j + 1] + 1, current[j] + 1));
+            current.push(cost);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_examples() {
+        for example in Commands::examples() {
+            let typed = example.typed.trim_left_matches("/token ");
+            let text = CommandText::parse(typed, true);
+            assert_eq!(text.command, Some(example.command), "{}", example.typed);
+        }
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        assert_eq!(serde_json::to_string(&Get).unwrap(), "\"get\"");
+        assert_eq!(serde_json::from_str::<Commands>("\"get\"").unwrap(), Get);
+
+        for command in &[List, Get, Drop, AfterYou, Barge, Steal, Top, Export, Import, Whois, Delete, Ping,
+                         Rename, Backup, Help, Announce, Set, Freeze, Unfreeze, Swap, Position, Config,
+                         SwapNext, Purge, Describe, Stats, Holdtime, Watch, Unwatch, AutoDrop, Requeue] {
+            let json = serde_json::to_string(command).unwrap();
+            assert_eq!(json.trim_matches('"').parse::<Commands>().unwrap(), *command);
+            assert_eq!(serde_json::from_str::<Commands>(&json).unwrap(), *command);
         }
     }
+
+    #[test]
+    fn test_parse_strict() {
+        assert_eq!(Commands::parse("get", true), Some(Get));
+        assert_eq!(Commands::parse("g", true), None);
+        assert_eq!(Commands::suggest("g"), Some(Get));
+        assert_eq!(Commands::suggest("gte"), Some(Get));
+        assert_eq!(Commands::suggest("frobnicate"), None);
+    }
+
+    #[test]
+    fn test_command_text() {
+        let text = CommandText::parse("get", true);
+        assert_eq!((text.token_name, text.command, text.options), (None, Some(Get), None));
+
+        let text = CommandText::parse("whois <@U1|alice>", true);
+        assert_eq!((text.token_name, text.command, text.options),
+                   (None, Some(Whois), Some("<@U1|alice>")));
+
+        let text = CommandText::parse("demo list --mentions off", true);
+        assert_eq!((text.token_name, text.command, text.options),
+                   (Some("demo"), Some(List), Some("--mentions off")));
+
+        let text = CommandText::parse("frobnicate now", true);
+        assert_eq!((text.token_name, text.command_name, text.command), (None, "frobnicate", None));
+    }
+
+    #[test]
+    fn test_parse_abbreviations() {
+        assert_eq!(Commands::parse("get", false), Some(Get));
+        assert_eq!(Commands::parse("g", false), Some(Get));
+        assert_eq!(Commands::parse("AFT", false), Some(AfterYou));
+        assert_eq!(Commands::parse("", false), None);
+        assert_eq!(Commands::parse("x", false), None);
+    }
+
+    #[test]
+    fn test_parse_shortcuts() {
+        assert_eq!(Commands::parse("+", false), Some(Get));
+        assert_eq!(Commands::parse("-", false), Some(Drop));
+        assert_eq!(Commands::parse("+", true), None);
+        assert_eq!(Commands::parse("-", true), None);
+
+        let text = CommandText::parse("demo +", false);
+        assert_eq!((text.token_name, text.command), (Some("demo"), Some(Get)));
+    }
 }
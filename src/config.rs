@@ -1,19 +1,380 @@
+use serde::Serializer;
 use serde_json;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Read};
 use std::path::Path;
 
-#[derive(Deserialize)]
+use commands::Commands;
+use slack::ChannelId;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CommandConfig {
+    #[serde(serialize_with = "redact")]
     pub token: String,
+    /// Slack's signing secret. When set every request must carry a recent `X-Slack-Signature`
+    /// made with it, and signed requests can't be replayed. Unset, requests are trusted on
+    /// `token` alone.
+    #[serde(default, serialize_with = "redact_option")]
+    pub signing_secret: Option<String>,
+    /// The ids of users allowed to run admin commands
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// The ids of users allowed to run admin commands which affect every channel, eg `purge`
+    #[serde(default)]
+    pub super_admins: Vec<String>,
+    /// The most named tokens a channel may have, besides its default token
+    #[serde(default)]
+    pub max_tokens_per_channel: Option<usize>,
+    /// How many seconds each user must wait between uses of a command, by command name
+    #[serde(default)]
+    pub command_cooldowns: HashMap<String, u64>,
+    /// Warn in `list` and `get` responses when a queue has more than this many users
+    #[serde(default)]
+    pub long_queue_threshold: Option<usize>,
+    /// How many times someone may barge within `barge_window_secs` before their next barge sends
+    /// them to the back of the queue instead. Unlimited when unset.
+    #[serde(default)]
+    pub barge_limit: Option<usize>,
+    #[serde(default = "default_barge_window_secs")]
+    pub barge_window_secs: u64,
+    /// Refuse to let anyone steal the token without saying why
+    #[serde(default)]
+    pub require_steal_reason: bool,
+    /// How many seconds someone must wait after leaving a queue before they can join it again.
+    /// Rejoining is always allowed when unset.
+    #[serde(default)]
+    pub rejoin_cooldown_secs: Option<u64>,
+    /// How many seconds a new holder is safe from having the token stolen. Stealing is always
+    /// allowed when unset.
+    #[serde(default)]
+    pub steal_grace_secs: Option<u64>,
+    /// The most commands a channel may send in `channel_rate_window_secs`, however many people send
+    /// them. Unlimited when unset.
+    #[serde(default)]
+    pub channel_rate_limit: Option<usize>,
+    #[serde(default = "default_channel_rate_window_secs")]
+    pub channel_rate_window_secs: u64,
+    /// Only accept commands by their full name, rather than also accepting abbreviations
+    #[serde(default)]
+    pub strict_commands: bool,
+    /// The most people allowed to wait behind the holder. Unlimited when unset.
+    #[serde(default)]
+    pub max_waiting: Option<usize>,
+    /// Cut names longer than this many characters short in lists which don't mention anyone.
+    /// Names are never shortened when unset.
+    #[serde(default)]
+    pub max_name_len: Option<usize>,
+    /// Only tell the person dropping, rather than the whole channel, when their drop empties the
+    /// queue
+    #[serde(default)]
+    pub quiet_empty_drop: bool,
+    /// `purge` drops holders who have held a token for longer than this many seconds. Holders are
+    /// never expired when unset.
+    #[serde(default)]
+    pub stale_holder_secs: Option<u64>,
+    /// An ephemeral tip shown to people the first time they use the bot in a channel. Set to
+    /// `null` to disable.
+    #[serde(default = "default_onboarding_message")]
+    pub onboarding_message: Option<String>,
+    /// Remind holders once they have held a token for this many seconds. Reminders are disabled
+    /// when this is unset.
+    #[serde(default)]
+    pub reminder_threshold_secs: Option<u64>,
+    /// The minimum number of seconds between two reminders to the same holder
+    #[serde(default = "default_reminder_interval_secs")]
+    pub reminder_interval_secs: u64,
+    /// A bot token for the Slack Web API, used to show people's display names rather than their
+    /// usernames
+    #[serde(default, serialize_with = "redact_option")]
+    pub slack_bot_token: Option<String>,
+    /// An emoji to react with, eg `wave`, to the message someone pressed a button on to get the
+    /// token. Needs `slack_bot_token`. No reaction is added when unset.
+    #[serde(default)]
+    pub get_reaction: Option<String>,
+    /// Append a JSON line describing every handled command to this file. No audit log is kept
+    /// when this is unset.
+    #[serde(default)]
+    pub audit_log_path: Option<String>,
+    /// Start with the queues saved in this file, a JSON array like the one `/token backup` gives
+    #[serde(default)]
+    pub restore_path: Option<String>,
+    /// Settings which differ in particular channels, by channel id
+    #[serde(default, skip_serializing)]
+    pub channel_overrides: HashMap<ChannelId, PartialConfig>,
+}
+
+/// The settings which can be overridden for a channel. Anything left unset keeps its global value.
+#[derive(Deserialize, Clone, Default)]
+pub struct PartialConfig {
+    #[serde(default)]
+    pub command_cooldowns: Option<HashMap<String, u64>>,
+    #[serde(default)]
+    pub long_queue_threshold: Option<usize>,
+    #[serde(default)]
+    pub barge_limit: Option<usize>,
+    #[serde(default)]
+    pub barge_window_secs: Option<u64>,
+    #[serde(default)]
+    pub require_steal_reason: Option<bool>,
+    #[serde(default)]
+    pub strict_commands: Option<bool>,
+    #[serde(default)]
+    pub quiet_empty_drop: Option<bool>,
+    #[serde(default)]
+    pub max_waiting: Option<usize>,
+    #[serde(default)]
+    pub steal_grace_secs: Option<u64>,
+    #[serde(default)]
+    pub rejoin_cooldown_secs: Option<u64>,
+    #[serde(default)]
+    pub channel_rate_limit: Option<usize>,
+    #[serde(default)]
+    pub channel_rate_window_secs: Option<u64>,
+}
+
+/// Keep secrets out of anything showing the config
+fn redact<S>(_secret: &String, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    serializer.serialize_str("<redacted>")
+}
+
+fn redact_option<S>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    match *secret {
+        Some(ref secret) => redact(secret, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A setting in the config which can't be used
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub field: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid `{}` in config: {}", self.field, self.reason)
+    }
+}
+
+fn positive<T: PartialEq + Default>(field: &str, value: Option<T>) -> Result<(), ConfigError> {
+    match value {
+        Some(ref value) if *value == T::default() => {
+            Err(ConfigError {
+                field: field.to_string(),
+                reason: "must be more than 0, leave it out to turn it off",
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn default_onboarding_message() -> Option<String> {
+    Some("Welcome! Use `/token get` to join the queue, `/token list` to see who is waiting and \
+          `/token drop` when you are done. `/token help` lists every command."
+        .to_string())
+}
+
+fn default_barge_window_secs() -> u64 {
+    60 * 60
+}
+
+fn default_channel_rate_window_secs() -> u64 {
+    60
+}
+
+fn default_reminder_interval_secs() -> u64 {
+    30 * 60
 }
 
 impl CommandConfig {
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.admins.iter().any(|admin| admin == user_id)
+    }
+
+    pub fn is_super_admin(&self, user_id: &str) -> bool {
+        self.super_admins.iter().any(|admin| admin == user_id)
+    }
+
+    /// The settings to use in a channel, with any overrides for it applied
+    pub fn for_channel(&self, channel_id: &str) -> CommandConfig {
+        let mut config = self.clone();
+        if let Some(overrides) = self.channel_overrides.get(channel_id) {
+            if let Some(ref command_cooldowns) = overrides.command_cooldowns {
+                config.command_cooldowns = command_cooldowns.clone();
+            }
+            if overrides.long_queue_threshold.is_some() {
+                config.long_queue_threshold = overrides.long_queue_threshold;
+            }
+            if overrides.barge_limit.is_some() {
+                config.barge_limit = overrides.barge_limit;
+            }
+            if let Some(barge_window_secs) = overrides.barge_window_secs {
+                config.barge_window_secs = barge_window_secs;
+            }
+            if let Some(require_steal_reason) = overrides.require_steal_reason {
+                config.require_steal_reason = require_steal_reason;
+            }
+            if let Some(strict_commands) = overrides.strict_commands {
+                config.strict_commands = strict_commands;
+            }
+            if let Some(quiet_empty_drop) = overrides.quiet_empty_drop {
+                config.quiet_empty_drop = quiet_empty_drop;
+            }
+            if overrides.max_waiting.is_some() {
+                config.max_waiting = overrides.max_waiting;
+            }
+            if overrides.steal_grace_secs.is_some() {
+                config.steal_grace_secs = overrides.steal_grace_secs;
+            }
+            if overrides.rejoin_cooldown_secs.is_some() {
+                config.rejoin_cooldown_secs = overrides.rejoin_cooldown_secs;
+            }
+            if overrides.channel_rate_limit.is_some() {
+                config.channel_rate_limit = overrides.channel_rate_limit;
+            }
+            if let Some(channel_rate_window_secs) = overrides.channel_rate_window_secs {
+                config.channel_rate_window_secs = channel_rate_window_secs;
+            }
+        }
+        config
+    }
+
+    /// The settings as pretty JSON, with secrets redacted
+    pub fn describe(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_else(|e| format!("Unable to show the config: {}", e))
+    }
+
+    /// Check the settings make sense, including in every channel with overrides
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.token.is_empty() {
+            return Err(ConfigError {
+                field: "token".to_string(),
+                reason: "must not be empty",
+            });
+        }
+        self.validate_limits("")?;
+        for channel_id in self.channel_overrides.keys() {
+            self.for_channel(channel_id).validate_limits(&format!("channel_overrides.{}.", channel_id))?;
+        }
+        Ok(())
+    }
+
+    /// Check the settings which can be overridden, naming fields with `prefix`
+    fn validate_limits(&self, prefix: &str) -> Result<(), ConfigError> {
+        for (name, &cooldown) in &self.command_cooldowns {
+            let field = format!("{}command_cooldowns.{}", prefix, name);
+            if name.parse::<Commands>().is_err() {
+                return Err(ConfigError {
+                    field: field,
+                    reason: "is not a command",
+                });
+            }
+            positive(&field, Some(cooldown))?;
+        }
+        positive(&format!("{}max_tokens_per_channel", prefix), self.max_tokens_per_channel)?;
+        positive(&format!("{}long_queue_threshold", prefix), self.long_queue_threshold)?;
+        positive(&format!("{}max_name_len", prefix), self.max_name_len)?;
+        positive(&format!("{}barge_limit", prefix), self.barge_limit)?;
+        positive(&format!("{}barge_window_secs", prefix), Some(self.barge_window_secs))?;
+        positive(&format!("{}steal_grace_secs", prefix), self.steal_grace_secs)?;
+        positive(&format!("{}rejoin_cooldown_secs", prefix), self.rejoin_cooldown_secs)?;
+        positive(&format!("{}channel_rate_limit", prefix), self.channel_rate_limit)?;
+        positive(&format!("{}channel_rate_window_secs", prefix), Some(self.channel_rate_window_secs))?;
+        positive(&format!("{}stale_holder_secs", prefix), self.stale_holder_secs)?;
+        positive(&format!("{}reminder_threshold_secs", prefix), self.reminder_threshold_secs)?;
+        positive(&format!("{}reminder_interval_secs", prefix), Some(self.reminder_interval_secs))
+    }
+
+    /// Load and validate the config
     pub fn from_path(path: &Path) -> io::Result<CommandConfig> {
-        let mut file = File::open(path)?;
+        CommandConfig::from_reader(File::open(path)?)
+    }
+
+    /// Load and validate the config from anything readable, such as stdin or a byte slice
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<CommandConfig> {
         let mut data = String::new();
-        file.read_to_string(&mut data)?;
+        reader.read_to_string(&mut data)?;
+
+        let config: CommandConfig = serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_channel() {
+        let config: CommandConfig = serde_json::from_str("{\"token\": \"secret\", \"barge_limit\": 3, \
+                                                           \"channel_overrides\": {\"C1\": \
+                                                           {\"require_steal_reason\": true, \"barge_limit\": 1}}}")
+            .unwrap();
+
+        let overridden = config.for_channel("C1");
+        assert!(overridden.require_steal_reason);
+        assert_eq!(overridden.barge_limit, Some(1));
+        // Settings without an override keep their global value
+        assert_eq!(overridden.token, "secret");
+        assert!(!overridden.strict_commands);
+
+        let other = config.for_channel("C2");
+        assert!(!other.require_steal_reason);
+        assert_eq!(other.barge_limit, Some(3));
+    }
+
+    #[test]
+    fn test_describe() {
+        let config: CommandConfig = serde_json::from_str("{\"token\": \"secret\", \"barge_limit\": 3, \
+                                                           \"slack_bot_token\": \"xoxb-secret\"}")
+            .unwrap();
+        let described = config.describe();
+        assert!(!described.contains("secret"));
+        assert!(described.contains("\"slack_bot_token\": \"<redacted>\""));
+        assert!(described.contains("\"token\": \"<redacted>\""));
+        assert!(described.contains("\"barge_limit\": 3"));
+        assert!(described.contains("\"barge_window_secs\": 3600"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let parse = |json: &str| -> CommandConfig { serde_json::from_str(json).unwrap() };
+        assert_eq!(parse("{\"token\": \"secret\", \"barge_limit\": 3, \"command_cooldowns\": {\"barge\": 60}}")
+                       .validate(),
+                   Ok(()));
+
+        let field = |json: &str| parse(json).validate().unwrap_err().field;
+        assert_eq!(field("{\"token\": \"\"}"), "token");
+        assert_eq!(field("{\"token\": \"secret\", \"max_tokens_per_channel\": 0}"),
+                   "max_tokens_per_channel");
+        assert_eq!(field("{\"token\": \"secret\", \"barge_window_secs\": 0}"), "barge_window_secs");
+        assert_eq!(field("{\"token\": \"secret\", \"command_cooldowns\": {\"frobnicate\": 60}}"),
+                   "command_cooldowns.frobnicate");
+        assert_eq!(field("{\"token\": \"secret\", \"channel_overrides\": {\"C1\": {\"barge_limit\": 0}}}"),
+                   "channel_overrides.C1.barge_limit");
+
+        let error = parse("{\"token\": \"secret\", \"long_queue_threshold\": 0}").validate().unwrap_err();
+        assert_eq!(error.to_string(),
+                   "invalid `long_queue_threshold` in config: must be more than 0, leave it out to turn it off");
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let config = CommandConfig::from_reader(&b"{\"token\": \"secret\", \"barge_limit\": 2}"[..]).unwrap();
+        assert_eq!(config.token, "secret");
+        assert_eq!(config.barge_limit, Some(2));
 
-        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let err = CommandConfig::from_reader(&b"{\"token\": \"secret\", \"barge_limit\": 0}"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = CommandConfig::from_reader(&b"not json"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
     }
 }
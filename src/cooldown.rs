@@ -0,0 +1,56 @@
+//! Per-user cooldowns for individual commands, eg to stop someone stealing the token repeatedly.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use clock::Timestamp;
+use slack::UserId;
+
+pub struct Cooldowns {
+    last_used: Mutex<HashMap<(UserId, String), Timestamp>>,
+}
+
+impl Cooldowns {
+    pub fn new() -> Cooldowns {
+        Cooldowns { last_used: Mutex::new(HashMap::new()) }
+    }
+
+    /// Check the user can use `command`, returning the number of seconds left to wait if they
+    /// used it less than `cooldown` seconds ago
+    pub fn check(&self, user_id: &str, command: &str, cooldown: u64, now: Timestamp) -> Result<(), u64> {
+        let last_used = self.last_used.lock().unwrap();
+        if let Some(&last) = last_used.get(&(user_id.to_owned(), command.to_owned())) {
+            let elapsed = now.saturating_sub(last);
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a use of `command` by the user, starting its cooldown
+    pub fn record(&self, user_id: &str, command: &str, now: Timestamp) {
+        self.last_used.lock().unwrap().insert((user_id.to_owned(), command.to_owned()), now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let cooldowns = Cooldowns::new();
+        assert_eq!(cooldowns.check("id0", "steal", 60, 1000), Ok(()));
+        // Checking alone doesn't start the cooldown
+        assert_eq!(cooldowns.check("id0", "steal", 60, 1000), Ok(()));
+        cooldowns.record("id0", "steal", 1000);
+        assert_eq!(cooldowns.check("id0", "steal", 60, 1020), Err(40));
+        // Other users and commands have their own cooldowns
+        assert_eq!(cooldowns.check("id1", "steal", 60, 1020), Ok(()));
+        assert_eq!(cooldowns.check("id0", "barge", 60, 1020), Ok(()));
+
+        assert_eq!(cooldowns.check("id0", "steal", 60, 1060), Ok(()));
+        cooldowns.record("id0", "steal", 1060);
+        assert_eq!(cooldowns.check("id0", "steal", 60, 1061), Err(59));
+    }
+}
@@ -0,0 +1,50 @@
+//! Errors a route can fail with. Each says whether the request or the bot was at fault, so the
+//! status code is right, while the body is still a message Slack can show.
+use rocket::http::Status;
+use rocket::response::{self, status, Responder};
+use rocket_contrib::JSON;
+
+use slack::SlackResponse;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RouteError {
+    pub status: Status,
+    pub message: &'static str,
+}
+
+impl RouteError {
+    /// The request was at fault, eg it had the wrong token
+    pub fn client(status: Status, message: &'static str) -> RouteError {
+        RouteError {
+            status: status,
+            message: message,
+        }
+    }
+
+    /// The bot was at fault, eg a lock was poisoned
+    pub fn internal(message: &'static str) -> RouteError {
+        RouteError {
+            status: Status::InternalServerError,
+            message: message,
+        }
+    }
+}
+
+/// Bare messages come from the bot's own failures, so they are internal
+impl From<&'static str> for RouteError {
+    fn from(message: &'static str) -> RouteError {
+        RouteError::internal(message)
+    }
+}
+
+impl<'r> Responder<'r> for RouteError {
+    fn respond(self) -> response::Result<'r> {
+        let text = if self.status.code < 500 {
+            format!("Sorry, that request was refused: {}", self.message)
+        } else {
+            println!("Unable to handle a request: {}", self.message);
+            "Sorry, something went wrong. Please try again.".to_string()
+        };
+        status::Custom(self.status, JSON(SlackResponse::ephemeral_text(&text))).respond()
+    }
+}
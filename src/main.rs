@@ -1,91 +1,798 @@
 #![feature(conservative_impl_trait, custom_derive, plugin)]
 #![plugin(rocket_codegen)]
 
+extern crate crypto;
+extern crate hyper;
+extern crate hyper_native_tls;
 extern crate rocket;
 extern crate rocket_contrib;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 
+use std::fs;
+use std::io::Read;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::process;
+use std::time::{Duration, Instant};
 
-use rocket::State;
+use rocket::{Rocket, State};
+use rocket::http::Status;
 use rocket::request::Form;
+use rocket::response::{content, status};
 use rocket_contrib::JSON;
 
+mod audit;
+mod clock;
 mod config;
 mod commands;
+mod cooldown;
+mod error;
 #[macro_use]
 mod macros;
+mod metrics;
+mod notify;
+mod onboarding;
+mod ratelimit;
+mod reminder;
+mod replay;
 mod slack;
 mod token;
+#[cfg(test)]
+mod test_utils;
 
-use commands::Commands;
+use commands::{Commands, CommandText};
+use error::RouteError;
 
 #[get("/")]
 fn index() -> &'static str {
     "Hello, World!"
 }
 
-#[post("/slack", format = "application/x-www-form-urlencoded", data = "<slash_form>")]
-fn slack<'a>(slash_form: Form<slack::SlashCommandData>,
+/// How long each command has recently taken to run
+#[get("/metrics")]
+fn metrics(metrics: State<metrics::Metrics>) -> String {
+    metrics.render()
+}
+
+// The `Form` guard already insists on a form content type, and unlike `format` it allows
+// parameters such as `; charset=utf-8`, which Slack sometimes sends
+#[post("/slack", data = "<slash_form>")]
+fn slack<'a>(_limit: slack::BodyLimit,
+             signature: slack::SlackSignature,
+             slash_form: Form<slack::SlashCommandData>,
              config: State<config::CommandConfig>,
-             tokens: State<token::Tokens>)
-             -> Result<JSON<slack::SlackResponse>, &'static str> {
-    let slash = slash_form.get();
+             tokens: State<token::Tokens>,
+             onboarding: State<onboarding::Onboarding>,
+             cooldowns: State<cooldown::Cooldowns>,
+             channel_limiter: State<ratelimit::ChannelLimiter>,
+             replays: State<replay::ReplayCache>,
+             notifier: State<Box<notify::Notifier>>,
+             audit_log: State<Option<audit::AuditLog>>,
+             display_names: State<Option<slack::DisplayNames>>,
+             metrics: State<metrics::Metrics>)
+             -> Result<JSON<slack::SlackResponse>, RouteError> {
+    handle_slash(slash_form.get(),
+                 slash_form.raw_form_string(),
+                 &signature,
+                 &config,
+                 &tokens,
+                 &onboarding,
+                 &cooldowns,
+                 &channel_limiter,
+                 &replays,
+                 &**notifier,
+                 &audit_log,
+                 &display_names,
+                 &metrics)
+}
+
+/// Slack's interactivity endpoint, for the buttons in `/token`'s home. The command a button stands
+/// for is run as if it had been typed, and the result posted back to the channel.
+#[post("/slack/actions", data = "<action_form>")]
+fn slack_actions(_limit: slack::BodyLimit,
+                 signature: slack::SlackSignature,
+                 action_form: Form<slack::InteractionForm>,
+                 config: State<config::CommandConfig>,
+                 tokens: State<token::Tokens>,
+                 onboarding: State<onboarding::Onboarding>,
+                 cooldowns: State<cooldown::Cooldowns>,
+                 channel_limiter: State<ratelimit::ChannelLimiter>,
+                 replays: State<replay::ReplayCache>,
+                 notifier: State<Box<notify::Notifier>>,
+                 audit_log: State<Option<audit::AuditLog>>,
+                 display_names: State<Option<slack::DisplayNames>>,
+                 metrics: State<metrics::Metrics>)
+                 -> Result<&'static str, RouteError> {
+    let slash = slack::parse_interaction(&action_form.get().payload)
+        .map_err(|_| RouteError::client(Status::BadRequest, "invalid interaction payload"))?;
+    let JSON(response) = handle_slash(&slash,
+                                      action_form.raw_form_string(),
+                                      &signature,
+                                      &config,
+                                      &tokens,
+                                      &onboarding,
+                                      &cooldowns,
+                                      &channel_limiter,
+                                      &replays,
+                                      &**notifier,
+                                      &audit_log,
+                                      &display_names,
+                                      &metrics)?;
+    // Slack ignores the body of a response to an interaction, so reply with the `response_url`
+    notifier.post_response(&slash.response_url, &response.in_thread(slash.thread_ts.as_ref()))
+        .map_err(|_| "unable to respond to interaction")?;
+    Ok("")
+}
+
+/// Authenticate a command and run it, from whichever endpoint it came in on
+fn handle_slash(slash: &slack::SlashCommandData,
+                body: &str,
+                signature: &slack::SlackSignature,
+                config: &config::CommandConfig,
+                tokens: &token::Tokens,
+                onboarding: &onboarding::Onboarding,
+                cooldowns: &cooldown::Cooldowns,
+                channel_limiter: &ratelimit::ChannelLimiter,
+                replays: &replay::ReplayCache,
+                notifier: &notify::Notifier,
+                audit_log: &Option<audit::AuditLog>,
+                display_names: &Option<slack::DisplayNames>,
+                metrics: &metrics::Metrics)
+                -> Result<JSON<slack::SlackResponse>, RouteError> {
     if slash.token != config.token {
-        return Err("token mismatch");
+        return Err(RouteError::client(Status::Forbidden, "token mismatch"));
+    }
+    if let Some(ref secret) = config.signing_secret {
+        let signature = signature.verify(secret, body, clock::now())
+            .map_err(|e| RouteError::client(Status::Unauthorized, e))?;
+        // Only remember verified signatures, as anyone could make up the others
+        replays.check(signature)?;
+    }
+    slack::validate_command(&slash).map_err(|e| RouteError::client(Status::BadRequest, e))?;
+    let config = config.for_channel(&slash.channel_id);
+    if let Some(limit) = config.channel_rate_limit {
+        let now = clock::now();
+        if !channel_limiter.check(&slash.team_id, &slash.channel_id, limit, config.channel_rate_window_secs, now) {
+            let text = "The bot is busy in this channel, please try again shortly";
+            return Ok(JSON(slack::SlackResponse::ephemeral_text(text)));
+        }
     }
-    slack::validate_command(&slash)?;
 
-    let ref command_text = slash.text;
-    let mut command_parts = command_text.splitn(1, ' ');
-    let command = command_parts.next().and_then(|s| s.parse().ok());
-    // [TODO]: Allow passing a second option for the "name" of the token, otherwise default to the
-    // channel token
-    // let options = command_parts.next();
+    // Anything from here on can rely on knowing who sent the command
+    let mut user = match token::User::try_new(slash.user_id.to_owned(), slash.user_name.to_owned()) {
+        Ok(user) => user,
+        Err(e) => return Ok(JSON(e.into())),
+    };
+    if let Some(ref display_names) = *display_names {
+        let user_name = display_names.resolve_display_name(notifier, &slash.user_id, &slash.user_name);
+        user = token::User::new(slash.user_id.to_owned(), user_name);
+    }
+
+    if let Some(tip) = onboarding.first_visit(&slash.team_id, &slash.channel_id, &slash.user_id) {
+        let tip = slack::SlackResponse::ephemeral_text(tip).in_thread(slash.thread_ts.as_ref());
+        let _ = notifier.post_response(&slash.response_url, &tip);
+    }
+
+    let normalized = slack::normalize_text(&slash.text);
+    // Nothing to parse, so show what can be done rather than looking for a command
+    if normalized.is_empty() {
+        return Ok(JSON(slack::home()));
+    }
+    let text = CommandText::parse(&normalized, config.strict_commands);
+    let response = handle_command(slash, &config, tokens, cooldowns, notifier, metrics, &user, &text);
+    if let Some(ref audit_log) = *audit_log {
+        let outcome = match response {
+            Ok(JSON(ref r)) if r.is_error() => "refused",
+            Ok(_) => "ok",
+            Err(e) => e,
+        };
+        let entry = audit::AuditEntry {
+            ts: clock::now(),
+            team: &slash.team_id,
+            channel: &slash.channel_id,
+            token: text.token_name.unwrap_or(token::DEFAULT_TOKEN),
+            user_id: &slash.user_id,
+            command: text.command.map(|c| c.name()).unwrap_or(text.command_name),
+            outcome: outcome,
+        };
+        if let Err(e) = audit_log.record(&entry) {
+            println!("Unable to write to the audit log: {}", e);
+        }
+    }
+    response.map_err(RouteError::from)
+}
+
+/// Check the command is allowed right now, then run it against the token it names
+fn handle_command(slash: &slack::SlashCommandData,
+                  config: &config::CommandConfig,
+                  tokens: &token::Tokens,
+                  cooldowns: &cooldown::Cooldowns,
+                  notifier: &notify::Notifier,
+                  metrics: &metrics::Metrics,
+                  user: &token::User,
+                  text: &CommandText)
+                  -> Result<JSON<slack::SlackResponse>, &'static str> {
+    let command = text.command;
+    let token_name = text.token_name.unwrap_or(token::DEFAULT_TOKEN);
 
-    let mut tokens_map = tokens.0.lock().unwrap();
-    let token_entry = tokens_map.entry((slash.team_id.to_owned(), slash.channel_id.to_owned()));
-    let token = token_entry.or_insert(Arc::new(RwLock::new(token::Token::new())));
-    let user = token::User::new(slash.user_id.to_owned(), slash.user_name.to_owned());
+    if let Some(command) = command {
+        if let Some(&cooldown) = config.command_cooldowns.get(command.name()) {
+            if let Err(remaining) = cooldowns.check(&slash.user_id, command.name(), cooldown, clock::now()) {
+                let text = format!("You can't use `{}` again for another {}",
+                                   command.name(),
+                                   clock::format_duration(remaining));
+                return Ok(JSON(slack::SlackResponse::ephemeral_text(&text)));
+            }
+        }
+    }
 
+    let token = match tokens.get_or_create_within(&slash.team_id,
+                                                  &slash.channel_id,
+                                                  token_name,
+                                                  config.max_tokens_per_channel) {
+        Ok(token) => token,
+        Err(existing) => {
+            let text = format!("This channel already has as many tokens as it's allowed: {}",
+                               existing.join(", "));
+            return Ok(JSON(slack::SlackResponse::ephemeral_text(&text)));
+        }
+    };
+    {
+        let mut token = token.write().map_err(|_| "unable to lock token (w)")?;
+        token.set_response_url(slash.response_url.to_owned());
+        // Keep the queue showing the caller's current name
+        if token.has_stale_name(user) {
+            token.replace_user(user.id(), user.clone());
+        }
+        if let Some(dropped) = token.expire_auto_drop(clock::now()) {
+            let text = format!("{} was dropped automatically after holding the token for {}",
+                               dropped.mention_only(),
+                               clock::format_duration(token.auto_drop_after(dropped.id()).unwrap_or(0)));
+            let _ = notifier.post_response(&slash.response_url, &slack::SlackResponse::inchannel_text(&text));
+        }
+    }
+
+    let started = Instant::now();
+    let mut response = run_command(slash, config, tokens, notifier, &token, user, text);
+    let succeeded = match response {
+        Ok(JSON(ref r)) => !r.is_error(),
+        Err(_) => false,
+    };
+    if let Some(command) = command {
+        metrics.record(command.name(), started.elapsed());
+        // Only a command that went through starts its cooldown, not one that was refused
+        if succeeded && config.command_cooldowns.contains_key(command.name()) {
+            cooldowns.record(&slash.user_id, command.name(), clock::now());
+        }
+    }
+    let front_ping = token.write().map_err(|_| "unable to lock token (w)")?.take_front_ping();
+    if let Some(holder) = front_ping {
+        // No need to tell someone they're at the front when they just put themselves there
+        if holder != *user {
+            let text = format!("{} you've reached the front of the queue and are holding the token",
+                               holder.mention_only());
+            let ping = slack::SlackResponse::inchannel_text(&text).in_thread(slash.thread_ts.as_ref());
+            let _ = notifier.post_response(&slash.response_url, &ping);
+        }
+    }
+    if command == Some(Commands::Get) {
+        if let (true, Some(emoji), Some(bot_token), Some(message_ts)) =
+            (succeeded, config.get_reaction.as_ref(), config.slack_bot_token.as_ref(), slash.message_ts.as_ref()) {
+            let _ = slack::add_reaction(notifier, bot_token, &slash.channel_id, message_ts, emoji);
+        }
+    }
+    if command == Some(Commands::List) || command == Some(Commands::Get) {
+        let len = token.read().map_err(|_| "unable to lock token (r)")?.len();
+        response = response.map(|JSON(r)| JSON(r.with_long_queue_warning(len, config.long_queue_threshold)));
+    }
+    if let Some(command) = command {
+        if command.mutates() {
+            let (report, watched) = {
+                let token = token.read().map_err(|_| "unable to lock token (r)")?;
+                let watched = token.watch_ts().map(|ts| (ts.to_owned(), token.clone()));
+                (token.as_report(), watched)
+            };
+            println!("{} {}/{}/{} {} {}: {}",
+                     clock::now(),
+                     slash.team_id,
+                     slash.channel_id,
+                     token_name,
+                     slash.user_id,
+                     command.name(),
+                     report);
+            // Update the live view from a copy, so the queue isn't locked while waiting on Slack
+            if let (Some(bot_token), Some((ts, queue))) = (config.slack_bot_token.as_ref(), watched) {
+                if let Err(e) = slack::update_watch(notifier, bot_token, &slash.channel_id, &ts, &queue) {
+                    println!("Unable to update the live queue in {}/{}: {}", slash.team_id, slash.channel_id, e);
+                }
+            }
+        }
+    }
+    response
+}
+
+/// How many people `holdtime` lists
+const HOLD_LEADERBOARD_SIZE: usize = 5;
+
+/// Run the parsed command against the token
+fn run_command(slash: &slack::SlashCommandData,
+               config: &config::CommandConfig,
+               tokens: &token::Tokens,
+               notifier: &notify::Notifier,
+               token: &token::TokenRef,
+               user: &token::User,
+               text: &CommandText)
+               -> Result<JSON<slack::SlackResponse>, &'static str> {
+    let (command_name, command, options) = (text.command_name, text.command, text.options);
     match command {
         Some(Commands::List) => {
-            printlist!(token)
+            if options == Some("--mentions off") {
+                let token = token.read().map_err(|_| "unable to lock token (r)")?;
+                if token.len() > 0 {
+                    let names = token.list_user_name().into_iter();
+                    return Ok(JSON(slack::format_name_list(None, names, config.max_name_len)));
+                }
+            }
+            if options == Some("--sort name") {
+                let token = token.read().map_err(|_| "unable to lock token (r)")?;
+                if let Some(holder) = token.front() {
+                    let text = format!("Sorted by name, {} is holding the token", holder.as_slack_str());
+                    return Ok(JSON(slack::format_list(Some(text), token.sorted_by_name().into_iter())));
+                }
+            }
+            let description = token.read().map_err(|_| "unable to lock token (r)")?.description().map(|d| d.to_owned());
+            match description {
+                Some(description) => printlist!(token, "_{}_", description),
+                None => printlist!(token),
+            }
         }
         Some(Commands::Get) => {
-            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).get(user.clone()) {
-                return Ok(JSON(slack::SlackResponse::ephemeral_text(e)));
+            if let Some((invited, position)) = options.and_then(slack::parse_user_at) {
+                if !config.is_admin(&slash.user_id) {
+                    return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can add someone else to the queue")));
+                }
+                let index = match token::queue_index(position) {
+                    Some(index) => index,
+                    None => return Ok(JSON(slack::SlackResponse::ephemeral_text("Positions start from #1"))),
+                };
+                let inserted = (*token.write().map_err(|_| "unable to lock token (w)")?)
+                    .insert_at(invited.clone(), index, config.max_waiting);
+                let index = match inserted {
+                    Ok(index) => index,
+                    Err(e) => return Ok(JSON(e.into())),
+                };
+                printlist!(token, "{} added {} to the queue at #{}", user.as_slack_str(), invited.as_slack_str(), token::queue_number(index))
+            }
+            // Describe the queue under the same lock as joining it, so that the message is right
+            // even if someone else joins at the same time
+            let message = {
+                let mut token = token.write().map_err(|_| "unable to lock token (w)")?;
+                if let Some(remaining) = token.rejoin_wait(user.id(), clock::now(), config.rejoin_cooldown_secs) {
+                    return Ok(JSON(token::TokenError::RejoinCooldown(remaining).into()));
+                }
+                let (flags, note): (Vec<&str>, Vec<&str>) =
+                    options.map(|o| o.split_whitespace().partition(|word| word.starts_with("--"))).unwrap_or_default();
+                let mut joining = user.clone();
+                joining.set_sticky(flags.contains(&"--sticky"));
+                joining.set_notify_on_front(flags.contains(&"--notify-on-front"));
+                if flags.contains(&"--priority") {
+                    if !config.is_admin(&slash.user_id) {
+                        return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can join with priority")));
+                    }
+                    joining.set_priority(true);
+                }
+                // Anything else is a note saying why they're joining
+                joining.set_note(if note.is_empty() { None } else { Some(note.join(" ")) });
+                match token.get_within(joining, config.max_waiting) {
+                    Ok(index) => slack::join_message(&user, index, token.front()),
+                    Err(e) => return Ok(JSON(e.into())),
+                }
+            };
+            printlist!(token, "{}", message)
+        }
+        Some(Commands::Drop) if options == Some("--dry-run") => {
+            let token = token.read().map_err(|_| "unable to lock token (r)")?;
+            if token.position(&user).is_none() {
+                return Ok(JSON(token::TokenError::NotQueued.into()));
+            }
+            let text = match token.compacted_clone_without(user.id()).front() {
+                Some(next) => format!("If you drop, {} will hold the token", next.as_slack_str()),
+                None => "If you drop, no one will hold the token".to_string(),
+            };
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&text)))
+        }
+        Some(Commands::Drop) if options.and_then(slack::parse_mention).map_or(false, |id| id != user.id()) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can drop someone else")));
+            }
+            // Checked by the guard
+            let target = options.and_then(slack::parse_mention).unwrap();
+            let kicked = (*token.write().map_err(|_| "unable to lock token (w)")?).kick(&target);
+            match kicked {
+                Ok(kicked) => {
+                    if let Some(ref bot_token) = config.slack_bot_token {
+                        let notice = format!("{} removed you from the `{}` queue",
+                                             user.as_slack_str(),
+                                             text.token_name.unwrap_or(token::DEFAULT_TOKEN));
+                        let _ = slack::post_ephemeral(notifier, bot_token, &slash.channel_id, kicked.id(), &notice);
+                    }
+                    printlist!(token, "{} removed {} from the queue", user.as_slack_str(), kicked.as_slack_str())
+                }
+                Err(e) => Ok(JSON(e.into())),
             }
-            printlist!(token, "{} joined the queue", user.as_slack_str())
         }
         Some(Commands::Drop) => {
-            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).drop(&user) {
-                return Ok(JSON(slack::SlackResponse::ephemeral_text(e)));
+            let (message, emptied) = {
+                let mut token = token.write().map_err(|_| "unable to lock token (w)")?;
+                let held_for = if token.is_holding_id(&slash.user_id) {
+                    token.holder_since().map(|since| clock::now().saturating_sub(since))
+                } else {
+                    None
+                };
+                if let Err(e) = token.drop(&user) {
+                    return Ok(JSON(e.into()));
+                }
+                let mut message = slack::drop_message(&user, held_for, token.front());
+                if token.position(&user).is_some() {
+                    message = message + " and rejoined the back of the queue";
+                }
+                (message, token.len() == 0)
+            };
+            if emptied && config.quiet_empty_drop {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text(&message)));
             }
-            printlist!(token, "{} dropped the token", user.as_slack_str())
+            printlist!(token, "{}", message)
         }
         Some(Commands::AfterYou) => {
-            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).step_back(&user) {
-                return Ok(JSON(slack::SlackResponse::ephemeral_text(e)));
+            let places = match options.map(|o| o.parse::<usize>()) {
+                None => 1,
+                Some(Ok(places)) if places > 0 => places,
+                Some(_) => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token afteryou [places]`"))),
+            };
+            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).step_back_by(&user, places) {
+                return Ok(JSON(e.into()));
             };
             printlist!(token)
         }
-        Some(Commands::Barge) => {
-            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).to_front(&user) {
-                return Ok(JSON(slack::SlackResponse::ephemeral_text(e)));
+        Some(Commands::Requeue) => {
+            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).to_back(&user) {
+                return Ok(JSON(e.into()));
             };
-            printlist!(token, "{} barged to the front!", user.as_slack_str())
+            printlist!(token, "{} went to the back of the queue", user.as_slack_str())
+        }
+        Some(Commands::Barge) => {
+            let limit = config.barge_limit.map(|count| (count, config.barge_window_secs));
+            let barged = (*token.write().map_err(|_| "unable to lock token (w)")?).barge(&user, clock::now(), limit);
+            match barged {
+                Ok(token::Barged::ToFront) => printlist!(token, "{} barged to the front!", user.as_slack_str()),
+                Ok(token::Barged::ToBack) => {
+                    printlist!(token, "{} has barged too often, and has been sent to the back of the queue!", user.as_slack_str())
+                }
+                Err(token::TokenError::AlreadyHolding) => Ok(JSON(slack::already_holding(Commands::Barge))),
+                Err(e) => Ok(JSON(e.into())),
+            }
         }
         Some(Commands::Steal) => {
-            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).steal(&user) {
+            let message = match slack::steal_message(&user, options, config.require_steal_reason) {
+                Ok(message) => message,
+                Err(e) => return Ok(JSON(slack::error_response(e.to_string()))),
+            };
+            let stolen = (*token.write().map_err(|_| "unable to lock token (w)")?).steal_at(&user,
+                                                                                       clock::now(),
+                                                                                       config.steal_grace_secs);
+            match stolen {
+                Err(token::TokenError::AlreadyHolding) => return Ok(JSON(slack::already_holding(Commands::Steal))),
+                Err(e) => return Ok(JSON(e.into())),
+                Ok(_) => {}
+            }
+            printlist!(token, "{}", message)
+        }
+        Some(Commands::SwapNext) => {
+            let next = match options.and_then(slack::parse_mention) {
+                Some(next) => next,
+                None => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token swap-next @someone`"))),
+            };
+            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).choose_next(&user, &next) {
+                return Ok(JSON(e.into()));
+            }
+            printlist!(token, "{} chose <@{}> to go next", user.as_slack_str(), next)
+        }
+        Some(Commands::Top) => {
+            Ok(JSON(slack::format_holder(&*token.read().map_err(|_| "unable to lock token (r)")?)))
+        }
+        Some(Commands::Position) => {
+            let token = token.read().map_err(|_| "unable to lock token (r)")?;
+            Ok(JSON(slack::format_position(&token, user.id(), clock::now())))
+        }
+        Some(Commands::Export) => {
+            if options != Some("json") {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token export json`")));
+            }
+            match (*token.read().map_err(|_| "unable to lock token (r)")?).to_json() {
+                Ok(json) => Ok(JSON(slack::SlackResponse::ephemeral_text(&format!("```{}```", json)))),
+                Err(e) => Ok(JSON(slack::SlackResponse::ephemeral_text(&e))),
+            }
+        }
+        Some(Commands::Import) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can import a queue")));
+            }
+            let json = match options {
+                Some(json) if !json.is_empty() => json.trim_matches('`'),
+                _ => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token import <json>`"))),
+            };
+            let mut imported = match token::Token::from_json(json) {
+                Ok(imported) => imported,
+                Err(e) => return Ok(JSON(slack::SlackResponse::ephemeral_text(&e))),
+            };
+            imported.set_response_url(slash.response_url.to_owned());
+            *token.write().map_err(|_| "unable to lock token (w)")? = imported;
+            printlist!(token, "{} imported the queue", user.as_slack_str())
+        }
+        Some(Commands::Whois) => {
+            let target = match options.and_then(slack::parse_mention) {
+                Some(target) => target,
+                None => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token whois @someone`"))),
+            };
+            let token = token.read().map_err(|_| "unable to lock token (r)")?;
+            Ok(JSON(slack::format_whois(&token, &target, clock::now())))
+        }
+        Some(Commands::Delete) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can delete a queue")));
+            }
+            let name = match options {
+                Some(name) if !name.is_empty() => name,
+                _ => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token delete <name>`"))),
+            };
+            let removed = match tokens.remove(&slash.team_id, &slash.channel_id, name) {
+                Some(removed) => removed,
+                None => {
+                    let text = format!("There is no `{}` queue in this channel", name);
+                    return Ok(JSON(slack::SlackResponse::ephemeral_text(&text)));
+                }
+            };
+            let removed = removed.read().map_err(|_| "unable to lock token (r)")?;
+            if let (Some(url), true) = (removed.response_url(), removed.len() > 0) {
+                let waiters: Vec<String> = removed.iter().map(|u| u.as_slack_str()).collect();
+                let text = format!("The `{}` queue has been deleted, {} you are no longer in it", name, waiters.join(", "));
+                let _ = notifier.post_response(url, &slack::SlackResponse::inchannel_text(&text));
+            }
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&format!("Deleted the `{}` queue", name))))
+        }
+        Some(Commands::Rename) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can rename a token")));
+            }
+            let names: Vec<&str> = options.map(|o| o.split_whitespace().collect()).unwrap_or(vec![]);
+            if names.len() != 2 {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token rename <old> <new>`")));
+            }
+            if let Err(e) = tokens.rename(&slash.team_id, &slash.channel_id, names[0], names[1]) {
                 return Ok(JSON(slack::SlackResponse::ephemeral_text(e)));
+            }
+            let text = format!("{} renamed `{}` to `{}`", user.as_slack_str(), names[0], names[1]);
+            Ok(JSON(slack::SlackResponse::inchannel_text(&text)))
+        }
+        Some(Commands::Describe) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can describe a token")));
+            }
+            let mut parts = options.unwrap_or("").splitn(2, ' ');
+            let name = parts.next().unwrap_or("");
+            if name.is_empty() {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token describe <name> <text>`")));
+            }
+            let description = parts.next().map(|d| d.trim()).and_then(|d| if d.is_empty() { None } else { Some(d) });
+            let described = match tokens.for_channel(&slash.team_id, &slash.channel_id)
+                .into_iter()
+                .find(|&(ref token_name, _)| token_name == name) {
+                Some((_, described)) => described,
+                None => {
+                    let text = format!("There is no `{}` queue in this channel", name);
+                    return Ok(JSON(slack::SlackResponse::ephemeral_text(&text)));
+                }
+            };
+            described.write()
+                .map_err(|_| "unable to lock token (w)")?
+                .set_description(description.map(|d| d.to_owned()));
+            let text = match description {
+                Some(description) => format!("Described `{}` as: {}", name, description),
+                None => format!("Cleared the description of `{}`", name),
+            };
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&text)))
+        }
+        Some(Commands::Backup) => {
+            let snapshots = tokens.snapshot_channel(&slash.team_id, &slash.channel_id);
+            match serde_json::to_string(&snapshots) {
+                Ok(json) => Ok(JSON(slack::SlackResponse::ephemeral_text(&format!("```{}```", json)))),
+                Err(_) => Err("unable to serialize backup"),
+            }
+        }
+        Some(Commands::Help) if options == Some("examples") => Ok(JSON(slack::send_help_examples())),
+        Some(Commands::Help) => {
+            match options.and_then(|o| o.parse::<Commands>().ok()) {
+                Some(command) => Ok(JSON(slack::send_command_help(command))),
+                None => Ok(JSON(slack::send_help(config.is_admin(&slash.user_id), config.is_super_admin(&slash.user_id)))),
+            }
+        }
+        Some(Commands::Announce) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can make announcements")));
+            }
+            match options {
+                Some(text) if !text.is_empty() => {
+                    let token = token.read().map_err(|_| "unable to lock token (r)")?;
+                    Ok(JSON(slack::format_announcement(&user, text, &token)))
+                }
+                _ => Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token announce <text>`"))),
+            }
+        }
+        Some(Commands::Set) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can change settings")));
+            }
+            let args: Vec<&str> = options.map(|o| o.split_whitespace().collect()).unwrap_or(vec![]);
+            if args.get(0) == Some(&"priority") {
+                let policy = match (args.get(1), args.get(2).map(|n| n.parse::<usize>())) {
+                    (Some(&"ahead"), None) => token::PriorityPolicy::JumpAhead,
+                    (Some(&"interleave"), Some(Ok(max))) if max > 0 => token::PriorityPolicy::Interleave(max),
+                    _ => {
+                        let text = "Usage: `/token set priority ahead` or `/token set priority interleave <count>`";
+                        return Ok(JSON(slack::SlackResponse::ephemeral_text(text)));
+                    }
+                };
+                (*token.write().map_err(|_| "unable to lock token (w)")?).set_priority_policy(policy);
+                let text = match policy {
+                    token::PriorityPolicy::JumpAhead => "Priority users now join ahead of everyone waiting".to_string(),
+                    token::PriorityPolicy::Interleave(max) => {
+                        format!("Priority users now join ahead, but no one waiting is passed by more than {}", max)
+                    }
+                };
+                return Ok(JSON(slack::SlackResponse::ephemeral_text(&text)));
+            }
+            let max_size = match (args.get(0), args.get(1)) {
+                (Some(&"maxsize"), Some(&"off")) => None,
+                (Some(&"maxsize"), Some(size)) => {
+                    match size.parse::<usize>() {
+                        Ok(size) if size > 0 => Some(size),
+                        _ => return Ok(JSON(slack::SlackResponse::ephemeral_text("The maximum size must be a positive number"))),
+                    }
+                }
+                _ => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token set maxsize <size|off>`"))),
+            };
+            (*token.write().map_err(|_| "unable to lock token (w)")?).set_max_size(max_size);
+            let text = match max_size {
+                Some(size) => format!("The queue is now limited to {} people", size),
+                None => "The queue is no longer limited".to_string(),
+            };
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&text)))
+        }
+        Some(Commands::Freeze) | Some(Commands::Unfreeze) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can freeze a queue")));
+            }
+            let frozen = command == Some(Commands::Freeze);
+            (*token.write().map_err(|_| "unable to lock token (w)")?).set_frozen(frozen);
+            if frozen {
+                printlist!(token, "{} froze the queue, it can't change until it is unfrozen", user.as_slack_str())
+            } else {
+                printlist!(token, "{} unfroze the queue", user.as_slack_str())
+            }
+        }
+        Some(Commands::Swap) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can reorder the queue")));
+            }
+            let positions: Vec<usize> = options.and_then(|o| o.split_whitespace().map(|p| p.parse().ok()).collect())
+                .unwrap_or(vec![]);
+            let indexes = (positions.get(0).and_then(|&a| token::queue_index(a)),
+                           positions.get(1).and_then(|&b| token::queue_index(b)));
+            let (a, b) = match (positions.len(), indexes) {
+                (2, (Some(a), Some(b))) => (a, b),
+                _ => return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token swap <position> <position>`"))),
+            };
+            if let Err(e) = (*token.write().map_err(|_| "unable to lock token (w)")?).swap_positions(a, b) {
+                return Ok(JSON(e.into()));
+            }
+            printlist!(token,
+                       "{} swapped #{} and #{}",
+                       user.as_slack_str(),
+                       token::queue_number(a),
+                       token::queue_number(b))
+        }
+        Some(Commands::Config) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can see the settings")));
+            }
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&format!("```{}```", config.describe()))))
+        }
+        Some(Commands::Purge) => {
+            if !config.is_super_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only super admins can purge every channel")));
+            }
+            let (expired, removed) = tokens.purge(clock::now(), config.stale_holder_secs);
+            if let (Some(bot_token), Some(stale_after)) = (config.slack_bot_token.as_ref(), config.stale_holder_secs) {
+                for &((_, ref channel, ref name), ref holder) in &expired {
+                    let text = format!("You were removed from the `{}` queue for holding the token for over {}",
+                                       name,
+                                       clock::format_duration(stale_after));
+                    let _ = slack::post_ephemeral(notifier, bot_token, channel, holder.id(), &text);
+                }
+            }
+            let text = format!("Purged {} empty queue(s) and expired {} stale holder(s)", removed, expired.len());
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&text)))
+        }
+        Some(Commands::Stats) => {
+            if !config.is_admin(&slash.user_id) {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Only admins can see stats")));
+            }
+            if options != Some("global") {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token stats global`")));
+            }
+            Ok(JSON(slack::format_global_stats(&tokens.global_stats(clock::now()))))
+        }
+        Some(Commands::Holdtime) => {
+            let token = token.read().map_err(|_| "unable to lock token (r)")?;
+            Ok(JSON(slack::format_hold_leaderboard(&token.hold_leaderboard(HOLD_LEADERBOARD_SIZE))))
+        }
+        Some(Commands::Watch) => {
+            let bot_token = match config.slack_bot_token {
+                Some(ref bot_token) => bot_token,
+                None => return Ok(JSON(slack::SlackResponse::ephemeral_text("Watching needs a Slack bot token"))),
             };
-            printlist!(token, "{} stole the token!", user.as_slack_str())
+            // Post a copy, so the queue isn't locked while waiting on Slack
+            let queue = token.read().map_err(|_| "unable to lock token (r)")?.clone();
+            match slack::post_watch(notifier, bot_token, &slash.channel_id, &queue) {
+                Ok(ts) => (*token.write().map_err(|_| "unable to lock token (w)")?).set_watch_ts(Some(ts)),
+                Err(e) => {
+                    let text = format!("Unable to post the live queue ({}), is the bot in this channel?", e);
+                    return Ok(JSON(slack::SlackResponse::ephemeral_text(&text)));
+                }
+            }
+            let text = "Posted a live view of the queue, pin it to keep it handy";
+            Ok(JSON(slack::SlackResponse::ephemeral_text(text)))
+        }
+        Some(Commands::Unwatch) => {
+            let mut token = token.write().map_err(|_| "unable to lock token (w)")?;
+            if token.watch_ts().is_none() {
+                return Ok(JSON(slack::SlackResponse::ephemeral_text("The queue isn't being watched")));
+            }
+            token.set_watch_ts(None);
+            Ok(JSON(slack::SlackResponse::ephemeral_text("The live view of the queue will no longer update")))
+        }
+        Some(Commands::AutoDrop) => {
+            let args: Vec<&str> = options.map(|o| o.split_whitespace().collect()).unwrap_or(vec![]);
+            let after = match (args.get(0), args.get(1).and_then(|d| clock::parse_duration(d))) {
+                (Some(&"on"), Some(after)) if after > 0 => Some(after),
+                (Some(&"off"), None) if args.len() == 1 => None,
+                _ => {
+                    return Ok(JSON(slack::SlackResponse::ephemeral_text("Usage: `/token auto-drop on <duration>`, eg \
+                                                                         `30m`, or `/token auto-drop off`")))
+                }
+            };
+            (*token.write().map_err(|_| "unable to lock token (w)")?).set_auto_drop(user.id(), after);
+            let text = match after {
+                Some(after) => format!("You'll be dropped automatically once you've held the token for {}",
+                                       clock::format_duration(after)),
+                None => "You'll no longer be dropped automatically".to_string(),
+            };
+            Ok(JSON(slack::SlackResponse::ephemeral_text(&text)))
+        }
+        Some(Commands::Ping) => {
+            Ok(JSON(slack::format_ping(config.restore_path.is_some(), config.signing_secret.is_some())))
+        }
+        None => {
+            match Commands::suggest(command_name) {
+                Some(suggestion) => {
+                    let text = format!("Unknown command `{}`, did you mean `/token {}`?", command_name, suggestion.name());
+                    Ok(JSON(slack::SlackResponse::ephemeral_text(&text)))
+                }
+                None => Ok(JSON(slack::send_help(config.is_admin(&slash.user_id), config.is_super_admin(&slash.user_id)))),
+            }
         }
-        _ => Ok(JSON(slack::send_help())),
     }
 }
 
@@ -102,12 +809,713 @@ fn format_list<'a>(text: Option<String>, token: &token::Token) -> Result<JSON<sl
     }
 }
 
-fn main() {
-    let config = config::CommandConfig::from_path(Path::new("./config.json")).unwrap();
-    let tokens = token::Tokens::new();
+/// Slack shows the body of non-200 responses as an opaque failure, so respond to requests Rocket
+/// rejects with a readable message instead.
+#[error(413)]
+fn payload_too_large() -> status::Custom<JSON<slack::SlackResponse>> {
+    let text = "That command was too long, please try something shorter";
+    status::Custom(Status::Ok, JSON(slack::SlackResponse::ephemeral_text(text)))
+}
+
+#[error(422)]
+fn unprocessable_entity() -> status::Custom<JSON<slack::SlackResponse>> {
+    let text = "Sorry, that request was missing some details, or had some twice. Is the slash command configured \
+                correctly?";
+    status::Custom(Status::Ok, JSON(slack::SlackResponse::ephemeral_text(text)))
+}
+
+/// Reached when a response can't be made, eg because it couldn't be serialized. The body is
+/// written out by hand, as serializing is what may have failed.
+#[error(500)]
+fn internal_server_error() -> status::Custom<content::JSON<&'static str>> {
+    let body = "{\"response_type\":\"ephemeral\",\"text\":\"Sorry, something went wrong. Please try again.\"}";
+    status::Custom(Status::InternalServerError, content::JSON(body))
+}
+
+fn rocket(config: config::CommandConfig, tokens: token::Tokens, notifier: Box<notify::Notifier>) -> Rocket {
+    let onboarding = onboarding::Onboarding::new(config.onboarding_message.clone());
+    let display_names = config.slack_bot_token.clone().map(slack::DisplayNames::new);
+    let audit_log = config.audit_log_path.as_ref().map(|path| audit::AuditLog::open(Path::new(path)).unwrap());
     rocket::ignite()
-        .mount("/", routes![index, slack])
+        .mount("/", routes![index, metrics, slack, slack_actions])
+        .catch(errors![payload_too_large, unprocessable_entity, internal_server_error])
         .manage(config)
         .manage(tokens)
-        .launch();
+        .manage(onboarding)
+        .manage(cooldown::Cooldowns::new())
+        .manage(ratelimit::ChannelLimiter::new())
+        .manage(replay::ReplayCache::new(replay::DEFAULT_CAPACITY))
+        .manage(notifier)
+        .manage(audit_log)
+        .manage(display_names)
+        .manage(metrics::Metrics::new())
+}
+
+/// Replace every queue with those saved at `path` by `/token backup`, returning how many there were
+fn restore(tokens: &token::Tokens, path: &str) -> Result<usize, String> {
+    let mut json = String::new();
+    if let Err(e) = fs::File::open(path).and_then(|mut file| file.read_to_string(&mut json)) {
+        return Err(e.to_string());
+    }
+    let snapshots = match serde_json::from_str(&json) {
+        Ok(snapshots) => snapshots,
+        Err(e) => return Err(format!("invalid backup: {}", e)),
+    };
+    tokens.import_snapshot(snapshots)
+}
+
+fn main() {
+    let config = config::CommandConfig::from_path(Path::new("./config.json")).unwrap();
+    let tokens = token::Tokens::new();
+    if let Some(ref path) = config.restore_path {
+        match restore(&tokens, path) {
+            Ok(restored) => println!("Restored {} queue(s) from {}", restored, path),
+            Err(e) => {
+                println!("Unable to restore queues from {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(threshold) = config.reminder_threshold_secs {
+        let settings = reminder::ReminderSettings {
+            threshold: threshold,
+            interval: config.reminder_interval_secs,
+        };
+        let notifier = notify::HttpNotifier::new().unwrap();
+        reminder::spawn(tokens.clone(), settings, Box::new(notifier), Duration::from_secs(60));
+    }
+    rocket(config, tokens, Box::new(notify::HttpNotifier::new().unwrap())).launch();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use rocket::http::{ContentType, Header, Method};
+    use rocket::testing::MockRequest;
+    use std::{env, fs};
+    use std::io::Read;
+
+    fn test_rocket() -> Rocket {
+        test_rocket_with("{\"token\": \"secret\"}")
+    }
+
+    fn test_rocket_with(config: &str) -> Rocket {
+        test_rocket_notifying(config, notify::MockNotifier::new())
+    }
+
+    fn test_rocket_notifying(config: &str, notifier: notify::MockNotifier) -> Rocket {
+        let config = serde_json::from_str(config).unwrap();
+        rocket(config, token::Tokens::new(), Box::new(notifier))
+    }
+
+    fn post_command(rocket: &Rocket, user_id: &str, text: &str) -> (Status, String) {
+        post_slack(rocket, &slack::SlashCommandData::from_user(user_id, text).to_form())
+    }
+
+    fn post_slack(rocket: &Rocket, body: &str) -> (Status, String) {
+        post_slack_as(rocket, ContentType::Form, body)
+    }
+
+    fn post_slack_as(rocket: &Rocket, content_type: ContentType, body: &str) -> (Status, String) {
+        let mut req = MockRequest::new(Method::Post, "/slack")
+            .header(content_type)
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(body);
+        let mut response = req.dispatch_with(rocket);
+        let body = response.body().and_then(|b| b.into_string()).unwrap_or_default();
+        (response.status(), body)
+    }
+
+    #[test]
+    fn test_form_charset() {
+        let rocket = test_rocket();
+        let content_type = "application/x-www-form-urlencoded; charset=utf-8".parse::<ContentType>().unwrap();
+        let command = slack::SlashCommandData { text: "ping".to_string(), ..Default::default() };
+        let (status, body) = post_slack_as(&rocket, content_type, &command.to_form());
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("pong"));
+    }
+
+    #[test]
+    fn test_missing_field() {
+        let rocket = test_rocket();
+        let (status, body) = post_slack(&rocket,
+                                        "token=secret&team_domain=example&channel_id=C1&channel_name=general&\
+                                         user_id=U1&user_name=alice&command=%2Ftoken&text=list&\
+                                         response_url=https%3A%2F%2Fexample.com");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("ephemeral"));
+        assert!(body.contains("missing some details"));
+    }
+
+    #[test]
+    fn test_oversized_body() {
+        let rocket = test_rocket();
+        let command = slack::SlashCommandData {
+            text: "a".repeat(slack::MAX_BODY_BYTES as usize),
+            ..Default::default()
+        };
+        let (status, body) = post_slack(&rocket, &command.to_form());
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("too long"));
+    }
+
+    #[test]
+    fn test_quiet_empty_drop() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"quiet_empty_drop\": true}");
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get");
+
+        // Someone is still waiting, so the channel hears about it
+        let (_, body) = post_command(&rocket, "U1", "drop");
+        assert!(body.contains("in_channel"));
+
+        let (status, body) = post_command(&rocket, "U2", "drop");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("ephemeral"));
+        assert!(!body.contains("in_channel"));
+    }
+
+    #[test]
+    fn test_onboarding_posted() {
+        let notifier = notify::MockNotifier::new();
+        let rocket = test_rocket_notifying("{\"token\": \"secret\"}", notifier.clone());
+        post_command(&rocket, "U1", "list");
+        post_command(&rocket, "U1", "list");
+
+        // Only the first visit gets the tip, posted to the command's response_url
+        let posts = notifier.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].0, "https://example.com");
+        assert!(posts[0].1.contains("\"response_type\":\"ephemeral\""));
+        assert!(posts[0].1.contains("Welcome!"));
+    }
+
+    #[test]
+    fn test_notify_on_front() {
+        let notifier = notify::MockNotifier::new();
+        let rocket = test_rocket_notifying("{\"token\": \"secret\", \"onboarding_message\": null}", notifier.clone());
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get --notify-on-front");
+        post_command(&rocket, "U3", "get");
+
+        post_command(&rocket, "U1", "drop");
+        let posts = notifier.posts();
+        assert_eq!(posts.len(), 1);
+        assert!(posts[0].1.contains("<@U2> you've reached the front"));
+
+        // U3 didn't opt in
+        post_command(&rocket, "U2", "drop");
+        assert_eq!(notifier.posts().len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_field() {
+        let rocket = test_rocket();
+        let body = format!("{}&team_id=T2", slack::SlashCommandData::from_user("U1", "ping").to_form());
+        let (status, body) = post_slack(&rocket, &body);
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("ephemeral"));
+        assert!(body.contains("had some twice"));
+        assert!(!body.contains("pong"));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let path = env::temp_dir().join("slack-token-test-audit-log.jsonl");
+        let _ = fs::remove_file(&path);
+        let config = format!("{{\"token\": \"secret\", \"audit_log_path\": {}}}",
+                             serde_json::to_string(path.to_str().unwrap()).unwrap());
+        let rocket = test_rocket_with(&config);
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "drop");
+
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let _ = fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["command"], "get");
+        assert_eq!(lines[0]["user_id"], "U1");
+        assert_eq!(lines[0]["channel"], "C1");
+        assert_eq!(lines[0]["token"], "default");
+        assert_eq!(lines[0]["outcome"], "ok");
+        assert_eq!(lines[1]["command"], "drop");
+        assert_eq!(lines[1]["outcome"], "refused");
+    }
+
+    #[test]
+    fn test_drop_mention() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"admins\": [\"U9\"]}");
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get");
+        post_command(&rocket, "U3", "get");
+
+        let (_, body) = post_command(&rocket, "U1", "drop <@U1|u1>");
+        assert!(body.contains("<@U1|u1> dropped the token"));
+
+        let (_, body) = post_command(&rocket, "U2", "drop <@U3|u3>");
+        assert!(body.contains("Only admins can drop someone else"));
+
+        let (_, body) = post_command(&rocket, "U9", "drop <@U3|u3>");
+        assert!(body.contains("<@U9|u9> removed <@U3|u3> from the queue"));
+        let (_, body) = post_command(&rocket, "U9", "list");
+        assert!(body.contains("<@U2|u2>"));
+        assert!(!body.contains("<@U3|u3>"));
+    }
+
+    #[test]
+    fn test_home() {
+        let rocket = test_rocket();
+        let (status, body) = post_command(&rocket, "U1", "");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("\"blocks\""));
+        assert!(body.contains("\"action_id\":\"get\""));
+    }
+
+    #[test]
+    fn test_blank_text() {
+        let rocket = test_rocket();
+        for text in &["", "   ", "\t", " \t \n"] {
+            let (status, body) = post_command(&rocket, "U1", text);
+            assert_eq!(status, Status::Ok);
+            assert!(body.contains("`/token help` lists every command"));
+        }
+    }
+
+    #[test]
+    fn test_actions() {
+        let notifier = notify::MockNotifier::new();
+        let rocket = test_rocket_notifying("{\"token\": \"secret\", \"onboarding_message\": null}", notifier.clone());
+        let payload = "{\"type\": \"block_actions\", \"token\": \"secret\", \
+                       \"team\": {\"id\": \"T1\", \"domain\": \"example\"}, \
+                       \"channel\": {\"id\": \"C1\", \"name\": \"general\"}, \
+                       \"user\": {\"id\": \"U1\", \"username\": \"alice\"}, \
+                       \"response_url\": \"https://hooks.slack.com/actions/1\", \
+                       \"actions\": [{\"action_id\": \"get\", \"value\": \"get\"}]}";
+        let body = format!("payload={}", slack::form_encode(payload));
+        let mut req = MockRequest::new(Method::Post, "/slack/actions")
+            .header(ContentType::Form)
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(&body);
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+
+        let posts = notifier.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].0, "https://hooks.slack.com/actions/1");
+        assert!(posts[0].1.contains("<@U1|alice> joined the queue and is holding the token"));
+
+        assert!(!posts[0].1.contains("thread_ts"));
+
+        // The button ran the same command as typing it would have
+        let (_, body) = post_command(&rocket, "U1", "list");
+        assert!(body.contains("<@U1|"));
+    }
+
+    #[test]
+    fn test_threaded_follow_up() {
+        let notifier = notify::MockNotifier::new();
+        let rocket = test_rocket_notifying("{\"token\": \"secret\"}", notifier.clone());
+        let command = slack::SlashCommandData {
+            text: "list".to_string(),
+            thread_ts: Some("1500000000.000100".to_string()),
+            ..Default::default()
+        };
+        let (status, _) = post_slack(&rocket, &command.to_form());
+        assert_eq!(status, Status::Ok);
+
+        // Without a thread, follow-ups go to the channel
+        let command = slack::SlashCommandData::from_user("U2", "list");
+        post_slack(&rocket, &command.to_form());
+
+        let posts = notifier.posts();
+        assert_eq!(posts.len(), 2);
+        assert!(posts[0].1.contains("\"thread_ts\":\"1500000000.000100\""));
+        assert!(!posts[1].1.contains("thread_ts"));
+    }
+
+    #[test]
+    fn test_get_reaction() {
+        let notifier = notify::MockNotifier::new();
+        let rocket = test_rocket_notifying("{\"token\": \"secret\", \"onboarding_message\": null, \
+                                            \"slack_bot_token\": \"xoxb-secret\", \"get_reaction\": \":wave:\"}",
+                                           notifier.clone());
+        let command = slack::SlashCommandData {
+            text: "get".to_string(),
+            message_ts: Some("1500000000.000100".to_string()),
+            ..Default::default()
+        };
+        post_slack(&rocket, &command.to_form());
+        // Joining again fails, so isn't celebrated
+        post_slack(&rocket, &command.to_form());
+        // Nothing to react to
+        post_command(&rocket, "U2", "get");
+
+        let reactions: Vec<(String, String)> = notifier.posts()
+            .into_iter()
+            .filter(|&(ref url, _)| url == "https://slack.com/api/reactions.add")
+            .collect();
+        assert_eq!(reactions.len(), 1);
+        assert_eq!(reactions[0].1,
+                   "{\"channel\":\"C1\",\"timestamp\":\"1500000000.000100\",\"name\":\"wave\"}");
+    }
+
+    #[test]
+    fn test_purge() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"admins\": [\"U1\"], \"super_admins\": [\"U9\"]}");
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U1", "other list");
+
+        // Channel admins can't sweep every channel
+        let (_, body) = post_command(&rocket, "U1", "purge");
+        assert!(body.contains("Only super admins"));
+
+        let (status, body) = post_command(&rocket, "U9", "purge");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("Purged 1 empty queue(s) and expired 0 stale holder(s)"));
+        let (_, body) = post_command(&rocket, "U1", "list");
+        assert!(body.contains("<@U1|"));
+    }
+
+    #[test]
+    fn test_describe() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"admins\": [\"U1\"]}");
+        post_command(&rocket, "U2", "prod get");
+
+        let (_, body) = post_command(&rocket, "U2", "describe prod mine now");
+        assert!(body.contains("Only admins"));
+        let (_, body) = post_command(&rocket, "U1", "describe staging deploy lock");
+        assert!(body.contains("There is no `staging` queue"));
+
+        let (_, body) = post_command(&rocket, "U1", "describe prod prod deploy lock");
+        assert!(body.contains("Described `prod` as: prod deploy lock"));
+        let (_, body) = post_command(&rocket, "U1", "prod list");
+        assert!(body.contains("\"text\":\"_prod deploy lock_\""));
+        assert!(body.contains("<@U2|"));
+        // Other tokens in the channel are unaffected
+        let (_, body) = post_command(&rocket, "U1", "list");
+        assert!(!body.contains("prod deploy lock"));
+
+        post_command(&rocket, "U1", "describe prod");
+        let (_, body) = post_command(&rocket, "U1", "prod list");
+        assert!(!body.contains("prod deploy lock"));
+    }
+
+    #[test]
+    fn test_get_reports_holder() {
+        let rocket = test_rocket();
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get");
+        let (_, body) = post_command(&rocket, "U3", "get");
+        assert!(body.contains("<@U3|u3> joined the queue, you're #3 with 2 people ahead of you and <@U1|"));
+    }
+
+    #[test]
+    fn test_kick_notifies() {
+        let notifier = notify::MockNotifier::new();
+        let rocket = test_rocket_notifying("{\"token\": \"secret\", \"admins\": [\"U1\"], \
+                                            \"onboarding_message\": null, \"slack_bot_token\": \"xoxb-secret\"}",
+                                           notifier.clone());
+        post_command(&rocket, "U2", "get");
+        let (_, body) = post_command(&rocket, "U1", "drop <@U2>");
+        assert!(body.contains("removed <@U2|"));
+
+        let ephemerals: Vec<(String, String)> = notifier.posts()
+            .into_iter()
+            .filter(|&(ref url, _)| url == "https://slack.com/api/chat.postEphemeral")
+            .collect();
+        assert_eq!(ephemerals.len(), 1);
+        assert!(ephemerals[0].1.contains("\"channel\":\"C1\",\"user\":\"U2\""));
+        assert!(ephemerals[0].1.contains("removed you from the `default` queue"));
+    }
+
+    #[test]
+    fn test_empty_user_id() {
+        let rocket = test_rocket();
+        let (status, body) = post_command(&rocket, "", "get");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("didn't say who you are"));
+    }
+
+    #[test]
+    fn test_holder_barge_steal() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"barge_limit\": 1}");
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get");
+
+        for command in &["barge", "barge", "steal"] {
+            let (_, body) = post_command(&rocket, "U1", command);
+            assert!(body.contains(&format!("You're already holding the token, so there's no need to {}. Use \
+                                            `/token drop` when you're done.",
+                                           command)));
+        }
+        // Barging as the holder doesn't count towards the limit or move anyone
+        let (_, body) = post_command(&rocket, "U1", "list");
+        assert!(body.find("<@U1|").unwrap() < body.find("<@U2|").unwrap());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let rocket = test_rocket();
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get");
+        post_command(&rocket, "U1", "list");
+
+        let mut req = MockRequest::new(Method::Get, "/metrics");
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body().and_then(|b| b.into_string()).unwrap();
+        assert!(body.contains("get count=2 "));
+        assert!(body.contains("list count=1 "));
+        assert!(!body.contains("drop"));
+    }
+
+    #[test]
+    fn test_get_note() {
+        let rocket = test_rocket();
+        post_command(&rocket, "U1", "get --sticky fixing login bug");
+        post_command(&rocket, "U2", "get");
+
+        let (_, body) = post_command(&rocket, "U1", "list");
+        assert!(body.contains("<@U1|u1> \u{2014} fixing login bug\\n"));
+        assert!(body.contains("<@U2|u2>\\n"));
+    }
+
+    /// A response which can never be serialized
+    struct Unserializable;
+
+    impl serde::Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    #[get("/unserializable")]
+    fn unserializable() -> JSON<Unserializable> {
+        JSON(Unserializable)
+    }
+
+    #[test]
+    fn test_serialization_failure() {
+        let rocket = test_rocket().mount("/test", routes![unserializable]);
+        let mut req = MockRequest::new(Method::Get, "/test/unserializable");
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert!(response.headers().get_one("Content-Type").unwrap().starts_with("application/json"));
+        let body = response.body().and_then(|b| b.into_string()).unwrap();
+        assert!(body.contains("\"response_type\":\"ephemeral\""));
+    }
+
+    #[test]
+    fn test_channel_rate_limit() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"channel_rate_limit\": 2}");
+        post_command(&rocket, "U1", "list");
+        post_command(&rocket, "U2", "list");
+        // A different user doesn't get round it
+        let (status, body) = post_command(&rocket, "U3", "list");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("busy in this channel"));
+
+        let command = slack::SlashCommandData {
+            channel_id: "C2".to_string(),
+            text: "list".to_string(),
+            ..Default::default()
+        };
+        let (_, body) = post_slack(&rocket, &command.to_form());
+        assert!(!body.contains("busy in this channel"));
+    }
+
+    #[test]
+    fn test_watch() {
+        let notifier = notify::MockNotifier::new();
+        notifier.respond("https://slack.com/api/chat.postMessage",
+                         "{\"ok\": true, \"ts\": \"1500000000.000100\"}");
+        let rocket = test_rocket_notifying("{\"token\": \"secret\", \"onboarding_message\": null, \
+                                            \"slack_bot_token\": \"xoxb-secret\"}",
+                                           notifier.clone());
+        let updates = || -> Vec<String> {
+            notifier.posts()
+                .into_iter()
+                .filter(|&(ref url, _)| url == "https://slack.com/api/chat.update")
+                .map(|(_, body)| body)
+                .collect()
+        };
+
+        let (_, body) = post_command(&rocket, "U1", "watch");
+        assert!(body.contains("Posted a live view"));
+        post_command(&rocket, "U1", "list");
+        assert!(updates().is_empty());
+
+        post_command(&rocket, "U2", "get");
+        let bodies = updates();
+        assert_eq!(bodies.len(), 1);
+        assert!(bodies[0].contains("\"ts\":\"1500000000.000100\""));
+        assert!(bodies[0].contains("1. <@U2|"));
+
+        post_command(&rocket, "U1", "unwatch");
+        post_command(&rocket, "U3", "get");
+        assert_eq!(updates().len(), 1);
+    }
+
+    #[test]
+    fn test_watch_failure() {
+        let notifier = notify::MockNotifier::new();
+        notifier.respond("https://slack.com/api/chat.postMessage",
+                         "{\"ok\": false, \"error\": \"not_in_channel\"}");
+        let rocket = test_rocket_notifying("{\"token\": \"secret\", \"slack_bot_token\": \"xoxb-secret\"}",
+                                           notifier);
+        let (_, body) = post_command(&rocket, "U1", "watch");
+        assert!(body.contains("Unable to post the live queue (not_in_channel)"));
+        let (_, body) = post_command(&rocket, "U1", "unwatch");
+        assert!(body.contains("isn't being watched"));
+    }
+
+    #[test]
+    fn test_auto_drop() {
+        let rocket = test_rocket();
+        let (_, body) = post_command(&rocket, "U1", "auto-drop on 30m");
+        assert!(body.contains("once you've held the token for 30m"));
+        let (_, body) = post_command(&rocket, "U1", "auto-drop on");
+        assert!(body.contains("Usage: `/token auto-drop on <duration>`"));
+        let (_, body) = post_command(&rocket, "U1", "auto-drop on 0");
+        assert!(body.contains("Usage"));
+
+        // Having only just got the token, the holder isn't dropped yet
+        post_command(&rocket, "U1", "get");
+        let (_, body) = post_command(&rocket, "U2", "list");
+        assert!(body.contains("<@U1|"));
+
+        let (_, body) = post_command(&rocket, "U1", "auto-drop off");
+        assert!(body.contains("You'll no longer be dropped automatically"));
+    }
+
+    #[test]
+    fn test_requeue() {
+        let rocket = test_rocket();
+        for user in &["U1", "U2", "U3"] {
+            post_command(&rocket, user, "get");
+        }
+
+        let (_, body) = post_command(&rocket, "U2", "requeue");
+        assert!(body.contains("You are not holding the token!"));
+
+        let (status, body) = post_command(&rocket, "U1", "requeue");
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("went to the back of the queue"));
+        let holder = body.find("<@U2|").unwrap();
+        assert!(holder < body.find("<@U3|").unwrap());
+        assert!(body.find("<@U3|").unwrap() < body.rfind("<@U1|").unwrap());
+    }
+
+    #[test]
+    fn test_error_statuses() {
+        let rocket = test_rocket();
+
+        // The wrong token is the caller's fault, but the reply is still one Slack can show
+        let mut slash = slack::SlashCommandData::from_user("U1", "get");
+        slash.token = "wrong".to_string();
+        let (status, body) = post_slack(&rocket, &slash.to_form());
+        assert_eq!(status, Status::Forbidden);
+        assert!(body.contains("\"response_type\":\"ephemeral\""));
+        assert!(body.contains("token mismatch"));
+
+        let body = "payload=not%20json";
+        let mut req = MockRequest::new(Method::Post, "/slack/actions")
+            .header(ContentType::Form)
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(body);
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_signed_requests() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"signing_secret\": \"shh\"}");
+        let form = slack::SlashCommandData::from_user("U1", "list").to_form();
+        let post_signed = |signature: Option<&str>, timestamp: &str| -> (Status, String) {
+            let mut req = MockRequest::new(Method::Post, "/slack")
+                .header(ContentType::Form)
+                .header(Header::new("Content-Length", form.len().to_string()))
+                .header(Header::new("X-Slack-Request-Timestamp", timestamp.to_string()));
+            if let Some(signature) = signature {
+                req = req.header(Header::new("X-Slack-Signature", signature.to_string()));
+            }
+            let mut req = req.body(&form);
+            let mut response = req.dispatch_with(&rocket);
+            let body = response.body().and_then(|b| b.into_string()).unwrap_or_default();
+            (response.status(), body)
+        };
+        let now = clock::now().to_string();
+        let signature = slack::sign("shh", &now, &form);
+
+        let (status, body) = post_signed(None, &now);
+        assert_eq!(status, Status::Unauthorized);
+        assert!(body.contains("missing signature"));
+        // Made up signatures are refused, and don't stop the real one being accepted
+        assert_eq!(post_signed(Some("v0=abc"), &now).0, Status::Unauthorized);
+        assert_eq!(post_signed(Some(&signature), &now).0, Status::Ok);
+        assert_eq!(post_signed(Some(&signature), &now).0, Status::Conflict);
+
+        let stale = (clock::now() - slack::MAX_SIGNATURE_AGE_SECS - 60).to_string();
+        let (status, body) = post_signed(Some(&slack::sign("shh", &stale, &form)), &stale);
+        assert_eq!(status, Status::Unauthorized);
+        assert!(body.contains("stale request timestamp"));
+    }
+
+    #[test]
+    fn test_route_error_status() {
+        assert_eq!(RouteError::from("unable to lock token (w)").status, Status::InternalServerError);
+        assert_eq!(RouteError::client(Status::Forbidden, "token mismatch").status, Status::Forbidden);
+    }
+
+    #[test]
+    fn test_priority() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"admins\": [\"U9\"]}");
+        post_command(&rocket, "U1", "get");
+        post_command(&rocket, "U2", "get");
+        let (_, body) = post_command(&rocket, "U3", "get --priority");
+        assert!(body.contains("Only admins can join with priority"));
+
+        let (_, body) = post_command(&rocket, "U9", "set priority interleave 0");
+        assert!(body.contains("Usage: `/token set priority ahead`"));
+        let (_, body) = post_command(&rocket, "U9", "set priority interleave 1");
+        assert!(body.contains("no one waiting is passed by more than 1"));
+
+        post_command(&rocket, "U9", "get --priority");
+        let (_, body) = post_command(&rocket, "U1", "list");
+        assert!(body.find("<@U9|").unwrap() < body.find("<@U2|").unwrap());
+    }
+
+    #[test]
+    fn test_cooldown_after_failure() {
+        let rocket = test_rocket_with("{\"token\": \"secret\", \"command_cooldowns\": {\"steal\": 60}}");
+        post_command(&rocket, "U1", "get");
+        // A steal that is refused doesn't start the cooldown
+        let (_, body) = post_command(&rocket, "U2", "steal");
+        assert!(body.contains("danger"));
+        post_command(&rocket, "U2", "get");
+        let (_, body) = post_command(&rocket, "U2", "steal");
+        assert!(!body.contains("You can't use `steal`"));
+        assert!(body.contains("stole the token"));
+
+        post_command(&rocket, "U1", "get");
+        let (_, body) = post_command(&rocket, "U1", "steal");
+        assert!(body.contains("stole the token"));
+        let (_, body) = post_command(&rocket, "U2", "steal");
+        assert!(body.contains("You can't use `steal` again for another"));
+    }
+
+    #[test]
+    fn test_restore_errors() {
+        let tokens = token::Tokens::new();
+        assert!(restore(&tokens, "/nonexistent/backup.json").is_err());
+
+        let path = env::temp_dir().join("token-test-restore.json");
+        fs::File::create(&path).and_then(|mut file| file.write_all(b"not json")).unwrap();
+        let result = restore(&tokens, path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().starts_with("invalid backup"));
+    }
 }
@@ -0,0 +1,117 @@
+//! How long commands take to run, for finding slow ones. Only the most recent samples are kept, so
+//! recording is cheap and memory stays bounded.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many samples to keep for each command
+const SAMPLES: usize = 1000;
+
+pub struct Metrics {
+    latencies: Mutex<HashMap<&'static str, VecDeque<u64>>>,
+}
+
+/// Latency percentiles for one command, in microseconds
+#[derive(Debug, PartialEq, Eq)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1000) as u64
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { latencies: Mutex::new(HashMap::new()) }
+    }
+
+    /// Record that running `command` took `duration`
+    pub fn record(&self, command: &'static str, duration: Duration) {
+        if let Ok(mut latencies) = self.latencies.lock() {
+            let samples = latencies.entry(command).or_insert_with(VecDeque::new);
+            if samples.len() >= SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back(micros(duration));
+        }
+    }
+
+    /// The percentiles of `command`'s recent latencies, if it has run
+    pub fn percentiles(&self, command: &str) -> Option<Percentiles> {
+        let latencies = match self.latencies.lock() {
+            Ok(latencies) => latencies,
+            Err(_) => return None,
+        };
+        let mut samples: Vec<u64> = match latencies.get(command) {
+            Some(samples) if !samples.is_empty() => samples.iter().cloned().collect(),
+            _ => return None,
+        };
+        samples.sort();
+        let at = |percent: usize| samples[(samples.len() - 1) * percent / 100];
+        Some(Percentiles {
+            count: samples.len(),
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+        })
+    }
+
+    /// Every command's percentiles as plain text, one command per line
+    pub fn render(&self) -> String {
+        let mut commands: Vec<&'static str> = match self.latencies.lock() {
+            Ok(latencies) => latencies.keys().cloned().collect(),
+            Err(_) => return String::new(),
+        };
+        commands.sort();
+        commands.into_iter()
+            .filter_map(|command| self.percentiles(command).map(|p| (command, p)))
+            .map(|(command, p)| {
+                format!("{} count={} p50={}us p90={}us p99={}us\n",
+                        command,
+                        p.count,
+                        p.p50,
+                        p.p90,
+                        p.p99)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.percentiles("get"), None);
+        for micros in 1..101 {
+            metrics.record("get", Duration::new(0, micros * 1000));
+        }
+        metrics.record("drop", Duration::from_secs(1));
+
+        assert_eq!(metrics.percentiles("get"),
+                   Some(Percentiles {
+                       count: 100,
+                       p50: 50,
+                       p90: 90,
+                       p99: 99,
+                   }));
+        assert_eq!(metrics.render(),
+                   "drop count=1 p50=1000000us p90=1000000us p99=1000000us\n\
+                    get count=100 p50=50us p90=90us p99=99us\n");
+    }
+
+    #[test]
+    fn test_bounded() {
+        let metrics = Metrics::new();
+        for _ in 0..SAMPLES + 10 {
+            metrics.record("get", Duration::from_millis(1));
+        }
+        assert_eq!(metrics.percentiles("get").unwrap().count, SAMPLES);
+    }
+}
@@ -0,0 +1,150 @@
+//! Outbound requests to Slack, eg messages to a slash command's `response_url` and Web API calls.
+use hyper::Client;
+use hyper::header::{Authorization, Bearer, ContentType};
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use serde_json;
+use std::io::Read;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use slack::SlackResponse;
+
+pub trait Notifier: Send + Sync {
+    /// POST the given JSON body to `url`
+    fn post_json(&self, url: &str, body: &str) -> Result<(), String>;
+
+    fn post_response(&self, url: &str, response: &SlackResponse) -> Result<(), String> {
+        let body = serde_json::to_string(response).map_err(|e| e.to_string())?;
+        self.post_json(url, &body)
+    }
+
+    /// GET `url` with the given bearer token, returning the body of a successful response
+    fn get_json(&self, url: &str, bearer_token: &str) -> Result<String, String>;
+
+    /// POST the given JSON body to a Web API method at `url`, with the given bearer token,
+    /// returning the body of a successful response
+    fn post_json_with_token(&self, url: &str, bearer_token: &str, body: &str) -> Result<String, String>;
+}
+
+pub struct HttpNotifier {
+    client: Client,
+}
+
+impl HttpNotifier {
+    pub fn new() -> Result<HttpNotifier, String> {
+        let ssl = NativeTlsClient::new().map_err(|e| e.to_string())?;
+        Ok(HttpNotifier { client: Client::with_connector(HttpsConnector::new(ssl)) })
+    }
+
+    fn post(&self, url: &str, bearer_token: Option<&str>, body: &str) -> Result<String, String> {
+        let mut request = self.client.post(url).header(ContentType::json()).body(body);
+        if let Some(bearer_token) = bearer_token {
+            request = request.header(Authorization(Bearer { token: bearer_token.to_owned() }));
+        }
+        let mut response = request.send().map_err(|e| e.to_string())?;
+        let mut text = String::new();
+        let _ = response.read_to_string(&mut text);
+        if response.status.is_success() {
+            Ok(text)
+        } else {
+            Err(format!("{}: {}", response.status, text))
+        }
+    }
+}
+
+impl Notifier for HttpNotifier {
+    fn post_json(&self, url: &str, body: &str) -> Result<(), String> {
+        self.post(url, None, body).map(|_| ())
+    }
+
+    fn post_json_with_token(&self, url: &str, bearer_token: &str, body: &str) -> Result<String, String> {
+        self.post(url, Some(bearer_token), body)
+    }
+
+    fn get_json(&self, url: &str, bearer_token: &str) -> Result<String, String> {
+        let mut response = self.client
+            .get(url)
+            .header(Authorization(Bearer { token: bearer_token.to_owned() }))
+            .send()
+            .map_err(|e| e.to_string())?;
+        let mut text = String::new();
+        response.read_to_string(&mut text).map_err(|e| e.to_string())?;
+        if response.status.is_success() {
+            Ok(text)
+        } else {
+            Err(format!("{}: {}", response.status, text))
+        }
+    }
+}
+
+/// Records what would have been posted instead of sending it, so tests stay off the network.
+/// Clones share their record, so a test can keep one while the code under test owns another.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MockNotifier {
+    posts: Arc<Mutex<Vec<(String, String)>>>,
+    gets: Arc<Mutex<Vec<String>>>,
+    responses: Arc<Mutex<HashMap<String, String>>>,
+}
+
+#[cfg(test)]
+impl MockNotifier {
+    pub fn new() -> MockNotifier {
+        MockNotifier::default()
+    }
+
+    /// Everything posted so far, as `(url, body)` pairs
+    pub fn posts(&self) -> Vec<(String, String)> {
+        self.posts.lock().unwrap().clone()
+    }
+
+    /// Answer GETs of `url` with `body`. Other GETs fail, and other Web API POSTs get `{"ok":true}`.
+    pub fn respond(&self, url: &str, body: &str) {
+        self.responses.lock().unwrap().insert(url.to_owned(), body.to_owned());
+    }
+
+    /// Every URL fetched so far
+    pub fn gets(&self) -> Vec<String> {
+        self.gets.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Notifier for MockNotifier {
+    fn post_json(&self, url: &str, body: &str) -> Result<(), String> {
+        self.posts.lock().unwrap().push((url.to_owned(), body.to_owned()));
+        Ok(())
+    }
+
+    fn post_json_with_token(&self, url: &str, _bearer_token: &str, body: &str) -> Result<String, String> {
+        self.post_json(url, body)?;
+        Ok(self.responses.lock().unwrap().get(url).cloned().unwrap_or_else(|| "{\"ok\":true}".to_string()))
+    }
+
+    fn get_json(&self, url: &str, _bearer_token: &str) -> Result<String, String> {
+        self.gets.lock().unwrap().push(url.to_owned());
+        self.responses.lock().unwrap().get(url).cloned().ok_or_else(|| "404 Not Found".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_response() {
+        let notifier = MockNotifier::new();
+        let recorder = notifier.clone();
+        notifier.post_response("https://hooks.slack.com/commands/1", &SlackResponse::inchannel_text("hello"))
+            .unwrap();
+
+        let posts = recorder.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].0, "https://hooks.slack.com/commands/1");
+        assert_eq!(posts[0].1, serde_json::to_string(&SlackResponse::inchannel_text("hello")).unwrap());
+        assert!(posts[0].1.contains("\"response_type\":\"in_channel\""));
+    }
+}
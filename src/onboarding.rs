@@ -0,0 +1,62 @@
+//! Tips for people using the bot in a channel for the first time.
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use slack::{ChannelId, TeamId, UserId};
+
+pub struct Onboarding {
+    message: Option<String>,
+    /// Who has been shown the tip, in each channel. Only kept in memory: `restore_path` reads back
+    /// what `/token backup` saved, which is a channel's queues, so there is nowhere to keep this
+    /// across restarts. At worst people see the tip once more after one.
+    seen: Mutex<HashMap<(TeamId, ChannelId), HashSet<UserId>>>,
+}
+
+impl Onboarding {
+    pub fn new(message: Option<String>) -> Onboarding {
+        Onboarding {
+            message: message,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that the user has used the bot in this channel, returning the onboarding message if
+    /// it is their first time.
+    pub fn first_visit(&self, team: &str, channel: &str, user: &str) -> Option<&str> {
+        let message = match self.message {
+            Some(ref message) => message.as_str(),
+            None => return None,
+        };
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(_) => return None,
+        };
+        let channel_seen = seen.entry((team.to_owned(), channel.to_owned())).or_insert_with(HashSet::new);
+        if channel_seen.insert(user.to_owned()) {
+            Some(message)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_visit() {
+        let onboarding = Onboarding::new(Some("tip".to_string()));
+        assert_eq!(onboarding.first_visit("team", "channel", "id0"), Some("tip"));
+        assert_eq!(onboarding.first_visit("team", "channel", "id0"), None);
+        // Seen users are tracked per channel
+        assert_eq!(onboarding.first_visit("team", "other", "id0"), Some("tip"));
+        assert_eq!(onboarding.first_visit("team", "channel", "id1"), Some("tip"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let onboarding = Onboarding::new(None);
+        assert_eq!(onboarding.first_visit("team", "channel", "id0"), None);
+    }
+}
@@ -0,0 +1,52 @@
+//! Per-channel request limits, so one busy channel can't keep the bot to itself however many people
+//! there are taking turns.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use clock::Timestamp;
+use slack::{ChannelId, TeamId};
+
+pub struct ChannelLimiter {
+    requests: Mutex<HashMap<(TeamId, ChannelId), VecDeque<Timestamp>>>,
+}
+
+impl ChannelLimiter {
+    pub fn new() -> ChannelLimiter {
+        ChannelLimiter { requests: Mutex::new(HashMap::new()) }
+    }
+
+    /// Record a request in the channel, unless it has already had `limit` requests in the last
+    /// `window` seconds, in which case it is refused with `false`.
+    pub fn check(&self, team: &str, channel: &str, limit: usize, window: u64, now: Timestamp) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        let recent = requests.entry((team.to_owned(), channel.to_owned())).or_insert_with(VecDeque::new);
+        while recent.front().map_or(false, |&at| now.saturating_sub(at) >= window) {
+            recent.pop_front();
+        }
+        if recent.len() >= limit {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let limiter = ChannelLimiter::new();
+        assert!(limiter.check("T1", "C1", 2, 60, 1000));
+        assert!(limiter.check("T1", "C1", 2, 60, 1010));
+        assert!(!limiter.check("T1", "C1", 2, 60, 1020));
+        // Other channels have their own limit
+        assert!(limiter.check("T1", "C2", 2, 60, 1020));
+        assert!(limiter.check("T2", "C1", 2, 60, 1020));
+
+        // Refused requests don't count, so the first one ageing out makes room
+        assert!(limiter.check("T1", "C1", 2, 60, 1060));
+        assert!(!limiter.check("T1", "C1", 2, 60, 1061));
+    }
+}
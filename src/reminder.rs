@@ -0,0 +1,158 @@
+//! Periodically nudges holders who have been holding a token for a long time.
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clock::{self, Timestamp};
+use notify::Notifier;
+use slack::SlackResponse;
+use token::{TokenRef, Tokens};
+
+pub struct ReminderSettings {
+    /// How long someone may hold a token before being reminded, in seconds
+    pub threshold: u64,
+    /// The minimum time between reminders to the same holder, in seconds
+    pub interval: u64,
+}
+
+/// Send a reminder for every token whose holder is due one, returning the number sent
+pub fn scan(tokens: &Tokens, now: Timestamp, settings: &ReminderSettings, notifier: &Notifier) -> usize {
+    // Work out who is due under the locks, but post without them so a slow Slack doesn't hold up
+    // every command
+    let due = match tokens.0.lock() {
+        Ok(tokens_map) => due_reminders(tokens_map.values(), now, settings),
+        Err(_) => return 0,
+    };
+    let mut sent = 0;
+    for (token, url, text) in due {
+        if notifier.post_response(&url, &SlackResponse::inchannel_text(&text)).is_err() {
+            continue;
+        }
+        if let Ok(mut token) = token.write() {
+            token.set_last_reminded(now);
+        }
+        sent += 1;
+    }
+    sent
+}
+
+/// The tokens whose holders are due a reminder, with the `response_url` to send it to and its text
+fn due_reminders<'a, I>(tokens: I, now: Timestamp, settings: &ReminderSettings) -> Vec<(TokenRef, String, String)>
+    where I: Iterator<Item = &'a TokenRef>
+{
+    let mut due = Vec::new();
+    for token_ref in tokens {
+        let token = match token_ref.read() {
+            Ok(token) => token,
+            Err(_) => continue,
+        };
+        let held_for = match token.holder_since() {
+            Some(since) => now.saturating_sub(since),
+            None => continue,
+        };
+        if held_for < settings.threshold {
+            continue;
+        }
+        if let Some(last) = token.last_reminded() {
+            if now.saturating_sub(last) < settings.interval {
+                continue;
+            }
+        }
+        if let (Some(holder), Some(url)) = (token.front(), token.response_url()) {
+            let text = format!("{} has been holding the token for {}, is it still needed?",
+                               holder.mention_only(),
+                               clock::format_duration(held_for));
+            due.push((token_ref.clone(), url.to_owned(), text));
+        }
+    }
+    due
+}
+
+/// Spawn a background thread which scans `tokens` for overdue holders every `period`
+pub fn spawn(tokens: Tokens,
+             settings: ReminderSettings,
+             notifier: Box<Notifier>,
+             period: Duration)
+             -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(period);
+        scan(&tokens, clock::now(), &settings, &*notifier);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::MockNotifier;
+    use token::{Token, User};
+
+    #[test]
+    fn test_scan() {
+        let tokens = Tokens::new();
+        let mut t = Token::new_with_holder(User::new("id0".to_string(), "name0".to_string()));
+        t.set_response_url("https://hooks.slack.com/commands/1".to_string());
+        let since = t.holder_since().unwrap();
+        *tokens.get_or_create("team", "channel", "default").write().unwrap() = t;
+        tokens.get_or_create("team", "empty", "default");
+
+        let settings = ReminderSettings {
+            threshold: 60,
+            interval: 600,
+        };
+        let notifier = MockNotifier::new();
+
+        assert_eq!(scan(&tokens, since + 59, &settings, &notifier), 0);
+        assert_eq!(scan(&tokens, since + 60, &settings, &notifier), 1);
+        // Only once per interval
+        assert_eq!(scan(&tokens, since + 600, &settings, &notifier), 0);
+        assert_eq!(scan(&tokens, since + 660, &settings, &notifier), 1);
+
+        let posts = notifier.posts();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0].0, "https://hooks.slack.com/commands/1");
+        assert!(posts[0].1.contains("<@id0> has been holding the token for 1m"));
+    }
+
+    /// Fails any post made while the token or the map of tokens is locked
+    struct LockCheckingNotifier {
+        tokens: Tokens,
+        token: TokenRef,
+    }
+
+    impl Notifier for LockCheckingNotifier {
+        fn post_json(&self, _url: &str, _body: &str) -> Result<(), String> {
+            match (self.tokens.0.try_lock(), self.token.try_write()) {
+                (Ok(_), Ok(_)) => Ok(()),
+                _ => Err("posted while locked".to_string()),
+            }
+        }
+
+        fn get_json(&self, _url: &str, _bearer_token: &str) -> Result<String, String> {
+            Ok("{}".to_string())
+        }
+
+        fn post_json_with_token(&self, _url: &str, _bearer_token: &str, _body: &str) -> Result<String, String> {
+            Ok("{}".to_string())
+        }
+    }
+
+    #[test]
+    fn test_scan_posts_unlocked() {
+        let tokens = Tokens::new();
+        let mut t = Token::new_with_holder(User::new("id0".to_string(), "name0".to_string()));
+        t.set_response_url("https://hooks.slack.com/commands/1".to_string());
+        let since = t.holder_since().unwrap();
+        let token = tokens.get_or_create("team", "channel", "default");
+        *token.write().unwrap() = t;
+
+        let settings = ReminderSettings {
+            threshold: 60,
+            interval: 600,
+        };
+        let notifier = LockCheckingNotifier {
+            tokens: tokens.clone(),
+            token: token.clone(),
+        };
+        assert_eq!(scan(&tokens, since + 60, &settings, &notifier), 1);
+        assert_eq!(token.read().unwrap().last_reminded(), Some(since + 60));
+    }
+}
@@ -0,0 +1,68 @@
+//! Protection against Slack requests being replayed, by remembering recently seen signatures.
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use rocket::http::Status;
+
+use error::RouteError;
+
+/// How many signatures to remember. Only verified signatures are remembered, and those older than
+/// `slack::MAX_SIGNATURE_AGE_SECS` are refused before getting here, so this only needs to cover
+/// that long at peak traffic.
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+pub struct ReplayCache {
+    capacity: usize,
+    seen: Mutex<(VecDeque<String>, HashSet<String>)>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> ReplayCache {
+        ReplayCache {
+            capacity: capacity,
+            seen: Mutex::new((VecDeque::new(), HashSet::new())),
+        }
+    }
+
+    /// Record a request's signature, rejecting it if it has been seen before
+    pub fn check(&self, signature: &str) -> Result<(), RouteError> {
+        let mut seen = self.seen.lock().map_err(|_| "unable to lock replay cache")?;
+        let (ref mut order, ref mut signatures) = *seen;
+        if signatures.contains(signature) {
+            return Err(RouteError::client(Status::Conflict, "request replayed"));
+        }
+        if order.len() >= self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                signatures.remove(&oldest);
+            }
+        }
+        order.push_back(signature.to_owned());
+        signatures.insert(signature.to_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let cache = ReplayCache::new(2);
+        assert_eq!(cache.check("v0=a"), Ok(()));
+        assert_eq!(cache.check("v0=a"), Err(RouteError::client(Status::Conflict, "request replayed")));
+        assert_eq!(cache.check("v0=b"), Ok(()));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let cache = ReplayCache::new(2);
+        cache.check("v0=a").unwrap();
+        cache.check("v0=b").unwrap();
+        cache.check("v0=c").unwrap();
+        // The oldest signature has been forgotten
+        assert_eq!(cache.check("v0=a"), Ok(()));
+        assert_eq!(cache.check("v0=c"), Err(RouteError::client(Status::Conflict, "request replayed")));
+        assert_eq!(cache.seen.lock().unwrap().0.len(), 2);
+    }
+}
@@ -1,11 +1,24 @@
-use token::User;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rocket::Outcome;
+use rocket::http::Status;
+use rocket::request::{self, FormItems, FromForm, FromFormValue, FromRequest, Request};
+use serde_json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use clock::{self, Timestamp};
+use commands::{CommandInfo, Commands};
+use notify::Notifier;
+use token::{self, GlobalStats, Token, TokenError, User};
 
 pub type TeamId = String;
 pub type ChannelId = String;
 pub type UserId = String;
 pub type UserName = String;
 
-#[derive(FromForm)]
 pub struct SlashCommandData {
     pub token: String,
     pub team_id: TeamId,
@@ -17,9 +30,253 @@ pub struct SlashCommandData {
     pub command: String,
     pub text: String,
     pub response_url: String,
+    /// The thread the command was run in, when Slack tells us
+    pub thread_ts: Option<String>,
+    /// The message the command came from, eg the one holding the button pressed
+    pub message_ts: Option<String>,
 }
 
-#[derive(Serialize)]
+/// Parsed by hand rather than derived so that a field given twice is rejected, instead of the
+/// last one silently winning. Fields we don't know are ignored, as Slack adds new ones over time.
+impl<'f> FromForm<'f> for SlashCommandData {
+    type Error = String;
+
+    fn from_form_items(items: &mut FormItems<'f>) -> Result<SlashCommandData, String> {
+        let mut fields: HashMap<&'f str, String> = HashMap::new();
+        for (key, value) in items {
+            let value = String::from_form_value(value).map_err(|_| format!("invalid value for `{}`", key))?;
+            if fields.insert(key, value).is_some() {
+                return Err(format!("duplicate field `{}`", key));
+            }
+        }
+        let thread_ts = fields.remove("thread_ts");
+        let message_ts = fields.remove("message_ts");
+        let mut field = |name: &str| fields.remove(name).ok_or_else(|| format!("missing field `{}`", name));
+        Ok(SlashCommandData {
+            token: field("token")?,
+            team_id: field("team_id")?,
+            team_domain: field("team_domain")?,
+            channel_id: field("channel_id")?,
+            channel_name: field("channel_name")?,
+            user_id: field("user_id")?,
+            user_name: field("user_name")?,
+            command: field("command")?,
+            text: field("text")?,
+            response_url: field("response_url")?,
+            thread_ts: thread_ts,
+            message_ts: message_ts,
+        })
+    }
+}
+
+/// A command from `U1` in `C1`, to be adjusted with struct update syntax
+#[cfg(test)]
+impl Default for SlashCommandData {
+    fn default() -> SlashCommandData {
+        SlashCommandData {
+            token: "secret".to_string(),
+            team_id: "T1".to_string(),
+            team_domain: "example".to_string(),
+            channel_id: "C1".to_string(),
+            channel_name: "general".to_string(),
+            user_id: "U1".to_string(),
+            user_name: "alice".to_string(),
+            command: "/token".to_string(),
+            text: String::new(),
+            response_url: "https://example.com".to_string(),
+            thread_ts: None,
+            message_ts: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl SlashCommandData {
+    /// The given user's command, named after their id
+    pub fn from_user(user_id: &str, text: &str) -> SlashCommandData {
+        SlashCommandData {
+            user_id: user_id.to_string(),
+            user_name: user_id.to_lowercase(),
+            text: text.to_string(),
+            ..SlashCommandData::default()
+        }
+    }
+
+    /// Encode the command the way Slack posts it
+    pub fn to_form(&self) -> String {
+        let fields = [("token", &self.token),
+                      ("team_id", &self.team_id),
+                      ("team_domain", &self.team_domain),
+                      ("channel_id", &self.channel_id),
+                      ("channel_name", &self.channel_name),
+                      ("user_id", &self.user_id),
+                      ("user_name", &self.user_name),
+                      ("command", &self.command),
+                      ("text", &self.text),
+                      ("response_url", &self.response_url)];
+        let mut encoded: Vec<String> =
+            fields.iter().map(|&(key, value)| format!("{}={}", key, form_encode(value))).collect();
+        if let Some(ref thread_ts) = self.thread_ts {
+            encoded.push(format!("thread_ts={}", form_encode(thread_ts)));
+        }
+        if let Some(ref message_ts) = self.message_ts {
+            encoded.push(format!("message_ts={}", form_encode(message_ts)));
+        }
+        encoded.join("&")
+    }
+}
+
+#[cfg(test)]
+pub fn form_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A request to the interactivity endpoint, which Slack sends as a form with a JSON `payload`
+#[derive(FromForm)]
+pub struct InteractionForm {
+    pub payload: String,
+}
+
+/// The parts of a `block_actions` interaction payload we use
+#[derive(Deserialize)]
+struct Interaction {
+    token: String,
+    team: InteractionTeam,
+    channel: InteractionChannel,
+    user: InteractionUser,
+    response_url: String,
+    actions: Vec<InteractionAction>,
+    #[serde(default)]
+    container: Option<InteractionContainer>,
+}
+
+#[derive(Deserialize)]
+struct InteractionTeam {
+    id: TeamId,
+    domain: String,
+}
+
+#[derive(Deserialize)]
+struct InteractionChannel {
+    id: ChannelId,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct InteractionUser {
+    id: UserId,
+    username: UserName,
+}
+
+#[derive(Deserialize)]
+struct InteractionContainer {
+    #[serde(default)]
+    thread_ts: Option<String>,
+    #[serde(default)]
+    message_ts: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct InteractionAction {
+    value: String,
+}
+
+/// Turn a button press into the slash command it stands for
+pub fn parse_interaction(payload: &str) -> Result<SlashCommandData, String> {
+    let interaction: Interaction = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    let text = match interaction.actions.into_iter().next() {
+        Some(action) => action.value,
+        None => return Err("no action".to_string()),
+    };
+    Ok(SlashCommandData {
+        token: interaction.token,
+        team_id: interaction.team.id,
+        team_domain: interaction.team.domain,
+        channel_id: interaction.channel.id,
+        channel_name: interaction.channel.name,
+        user_id: interaction.user.id,
+        user_name: interaction.user.username,
+        command: "/token".to_string(),
+        text: text,
+        response_url: interaction.response_url,
+        thread_ts: interaction.container.as_ref().and_then(|c| c.thread_ts.clone()),
+        message_ts: interaction.container.and_then(|c| c.message_ts),
+    })
+}
+
+/// Slash command payloads are small, so anything much bigger than this is not from Slack
+pub const MAX_BODY_BYTES: u64 = 4 * 1024;
+
+/// A request guard rejecting requests whose declared body is larger than `MAX_BODY_BYTES`
+pub struct BodyLimit;
+
+impl<'a, 'r> FromRequest<'a, 'r> for BodyLimit {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BodyLimit, ()> {
+        let length = request.headers().get_one("Content-Length").and_then(|l| l.parse::<u64>().ok());
+        match length {
+            Some(length) if length > MAX_BODY_BYTES => Outcome::Failure((Status::PayloadTooLarge, ())),
+            _ => Outcome::Success(BodyLimit),
+        }
+    }
+}
+
+/// How far a signed request's timestamp can be from now before it's refused, as Slack recommends
+pub const MAX_SIGNATURE_AGE_SECS: u64 = 5 * 60;
+
+/// The `X-Slack-Signature` and `X-Slack-Request-Timestamp` headers of a request, if it has them
+pub struct SlackSignature {
+    pub signature: Option<String>,
+    pub timestamp: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SlackSignature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<SlackSignature, ()> {
+        let header = |name: &str| request.headers().get_one(name).map(|s| s.to_owned());
+        Outcome::Success(SlackSignature {
+            signature: header("X-Slack-Signature"),
+            timestamp: header("X-Slack-Request-Timestamp"),
+        })
+    }
+}
+
+impl SlackSignature {
+    /// Check Slack signed `body` with `secret` within `MAX_SIGNATURE_AGE_SECS` of `now`, returning
+    /// the signature once it's known to be genuine
+    pub fn verify(&self, secret: &str, body: &str, now: Timestamp) -> Result<&str, &'static str> {
+        let (signature, timestamp) = match (self.signature.as_ref(), self.timestamp.as_ref()) {
+            (Some(signature), Some(timestamp)) => (signature, timestamp),
+            _ => return Err("missing signature"),
+        };
+        let signed_at = timestamp.parse::<Timestamp>().map_err(|_| "invalid request timestamp")?;
+        if now.saturating_sub(signed_at) > MAX_SIGNATURE_AGE_SECS ||
+           signed_at.saturating_sub(now) > MAX_SIGNATURE_AGE_SECS {
+            return Err("stale request timestamp");
+        }
+        if !fixed_time_eq(sign(secret, timestamp, body).as_bytes(), signature.as_bytes()) {
+            return Err("invalid signature");
+        }
+        Ok(signature)
+    }
+}
+
+/// Slack's `v0` signature: an HMAC-SHA256 of `v0:<timestamp>:<body>`, in hex
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(format!("v0:{}:{}", timestamp, body).as_bytes());
+    let hex: String = mac.result().code().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("v0={}", hex)
+}
+
+#[derive(Serialize, Debug, PartialEq)]
 enum ResponseType {
     #[serde(rename = "ephemeral")]
     Ephemeral,
@@ -28,24 +285,89 @@ enum ResponseType {
 }
 use self::ResponseType::*;
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, PartialEq)]
 pub struct SlackResponse {
     response_type: ResponseType,
     text: Option<String>,
     attachments: Vec<SlackAttachment>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    blocks: Vec<Block>,
+    /// Post into this thread rather than the channel, when sent to a `response_url`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_ts: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, PartialEq)]
 pub struct SlackAttachment {
     text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+}
+
+/// A Block Kit layout block. Only the parts we use are modelled.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Block {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<BlockText>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    elements: Vec<Button>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BlockText {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+}
+
+/// A button which runs the command in its `value` when pressed
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Button {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: BlockText,
+    action_id: String,
+    value: String,
+}
+
+impl Button {
+    fn for_command(label: &str, command: Commands) -> Button {
+        Button {
+            kind: "button",
+            text: BlockText {
+                kind: "plain_text",
+                text: label.to_owned(),
+            },
+            action_id: command.name().to_owned(),
+            value: command.name().to_owned(),
+        }
+    }
+}
+
+impl SlackAttachment {
+    pub fn new(text: String) -> SlackAttachment {
+        SlackAttachment {
+            text: text,
+            color: None,
+        }
+    }
 }
 
 impl SlackResponse {
+    /// Whether this response refuses what was asked, ie it's shown in red
+    pub fn is_error(&self) -> bool {
+        self.attachments.iter().any(|a| a.color.as_ref().map(|c| c.as_str()) == Some("danger"))
+    }
+
     pub fn ephemeral_text(text: &str) -> SlackResponse {
         SlackResponse {
             response_type: Ephemeral,
             text: Some(text.to_owned()),
-            attachments: vec![]
+            attachments: vec![],
+            blocks: vec![],
+            thread_ts: None,
         }
     }
 
@@ -53,26 +375,104 @@ impl SlackResponse {
         SlackResponse {
             response_type: InChannel,
             text: Some(text.to_owned()),
-            attachments: vec![]
+            attachments: vec![],
+            blocks: vec![],
+            thread_ts: None,
         }
     }
+
+    /// Send this into the thread `thread_ts`, if any, rather than the channel
+    pub fn in_thread(mut self, thread_ts: Option<&String>) -> SlackResponse {
+        self.thread_ts = thread_ts.cloned();
+        self
+    }
+
+    /// Add a footer warning that the queue is getting long if it has more than `threshold` users
+    pub fn with_long_queue_warning(mut self, len: usize, threshold: Option<usize>) -> SlackResponse {
+        if let Some(threshold) = threshold {
+            if len > threshold {
+                let warning = ":warning: This queue is getting long, is the resource stuck?";
+                self.attachments.push(SlackAttachment::new(warning.to_string()));
+            }
+        }
+        self
+    }
 }
 
-pub fn send_help() -> SlackResponse {
+/// List the commands the caller can use, so admin commands are only shown to admins
+pub fn send_help(is_admin: bool, is_super_admin: bool) -> SlackResponse {
+    let mut text = "Token manager. Use `/token get` to take hold of the token.\nOther commands available:\n"
+        .to_string();
+    let shown = |info: &&CommandInfo| if info.command.super_admin_only() {
+        is_super_admin
+    } else {
+        is_admin || !info.admin_only
+    };
+    for info in Commands::all().iter().filter(shown) {
+        text = text + "• `/token " + info.name + "` " + info.description + "\n";
+    }
     SlackResponse {
         response_type: Ephemeral,
         text: None,
-        attachments: vec![SlackAttachment {
-                              text: "
-Token manager. Use `/token get` to take hold of the token.
-\
-                                     Other commands available:
-• `/token get` adds yourself to \
-                                     the queue
-• `/token drop` removes yourself from the queue
-"
-                                  .to_string(),
-                          }],
+        attachments: vec![SlackAttachment::new(text)],
+        blocks: vec![],
+        thread_ts: None,
+    }
+}
+
+/// What `/token` on its own shows: buttons for the commands people use most
+pub fn home() -> SlackResponse {
+    SlackResponse {
+        response_type: Ephemeral,
+        text: Some("Token manager".to_string()),
+        attachments: vec![],
+        blocks: vec![Block {
+                         kind: "section",
+                         text: Some(BlockText {
+                             kind: "mrkdwn",
+                             text: "What would you like to do? `/token help` lists every command.".to_string(),
+                         }),
+                         elements: vec![],
+                     },
+                     Block {
+                         kind: "actions",
+                         text: None,
+                         elements: vec![Button::for_command("Get", Commands::Get),
+                                        Button::for_command("Drop", Commands::Drop),
+                                        Button::for_command("List", Commands::List)],
+                     }],
+        thread_ts: None,
+    }
+}
+
+/// A short transcript of typical use, for people who'd rather learn by example
+pub fn send_help_examples() -> SlackResponse {
+    let mut text = "A typical session:\n".to_string();
+    for example in Commands::examples() {
+        text.push_str(&format!("• `{}` \u{2014} {}\n", example.typed, example.note));
+    }
+    text.push_str("Use `/token help <command>` for more about any of them");
+    SlackResponse {
+        response_type: Ephemeral,
+        text: None,
+        attachments: vec![SlackAttachment::new(text)],
+        blocks: vec![],
+        thread_ts: None,
+    }
+}
+
+/// Explain a single command, with an example
+pub fn send_command_help(command: Commands) -> SlackResponse {
+    let info = command.info();
+    SlackResponse {
+        response_type: Ephemeral,
+        text: None,
+        attachments: vec![SlackAttachment::new(format!("`/token {}` {}\nFor example: `{}`",
+                                                       info.name,
+                                                       info.description,
+                                                       info.example))],
+        blocks: vec![],
+        thread_ts: None,
     }
 }
 
@@ -80,18 +480,458 @@ Token manager. Use `/token get` to take hold of the token.
 pub fn format_list<'a, I>(text: Option<String>, items: I) -> SlackResponse
     where I: Iterator<Item=&'a User>
 {
-    let string = String::new();
-    let string = items.fold(string, |acc, s| {
-        acc + ":large_blue_circle: " + &s.as_slack_str() + "\n"
-    });
-    let attachment = SlackAttachment { text: string };
+    format_lines(text, items.map(list_entry))
+}
+
+/// A user as shown in a list, along with why they are queued if they said
+fn list_entry(user: &User) -> String {
+    match user.note() {
+        Some(note) => format!("{} \u{2014} {}", user.as_slack_str(), note),
+        None => user.as_slack_str(),
+    }
+}
+
+/// Format a list of plain names, which unlike `format_list` won't mention anyone. Names longer
+/// than `max_len` characters are cut short, so one long name doesn't upset the layout.
+pub fn format_name_list<'a, I>(text: Option<String>, names: I, max_len: Option<usize>) -> SlackResponse
+    where I: Iterator<Item=&'a str>
+{
+    format_lines(text, names.map(|s| truncate_name(s, max_len)))
+}
+
+/// Shorten `name` to at most `max_len` characters, ending it with an ellipsis if it was cut
+pub fn truncate_name(name: &str, max_len: Option<usize>) -> String {
+    match max_len {
+        Some(max_len) if name.chars().count() > max_len => {
+            let kept: String = name.chars().take(max_len.saturating_sub(1)).collect();
+            kept + "\u{2026}"
+        }
+        _ => name.to_owned(),
+    }
+}
+
+fn format_lines<I>(text: Option<String>, lines: I) -> SlackResponse
+    where I: Iterator<Item=String>
+{
+    const BULLET: &'static str = ":large_blue_circle: ";
+    // Guess at a typical line so a long queue is built without growing the string repeatedly
+    let mut string = String::with_capacity(lines.size_hint().0 * (BULLET.len() + 32));
+    for line in lines {
+        string.push_str(BULLET);
+        string.push_str(&line);
+        string.push('\n');
+    }
+    let attachment = SlackAttachment::new(string);
     SlackResponse {
         response_type: InChannel,
         text: text.map(|s| s.to_owned()),
         attachments: vec![attachment],
+        blocks: vec![],
+        thread_ts: None,
+    }
+}
+
+/// Describe someone joining the queue at `index`
+pub fn join_message(user: &User, index: usize, holder: Option<&User>) -> String {
+    match holder {
+        Some(holder) if index > 0 => {
+            let ahead = if index == 1 { "1 person".to_string() } else { format!("{} people", index) };
+            format!("{} joined the queue, you're #{} with {} ahead of you and {} is holding the token",
+                    user.as_slack_str(),
+                    token::queue_number(index),
+                    ahead,
+                    holder.as_slack_str())
+        }
+        _ => format!("{} joined the queue and is holding the token", user.as_slack_str()),
+    }
+}
+
+/// Describe someone leaving the queue. `held_for` is how long they held the token for, if they
+/// were the holder, and `next` the new holder.
+pub fn drop_message(user: &User, held_for: Option<u64>, next: Option<&User>) -> String {
+    match (held_for, next) {
+        (Some(held_for), Some(next)) => {
+            format!("{} dropped the token (held for {}), {} is up",
+                    user.as_slack_str(),
+                    clock::format_duration(held_for),
+                    next.as_slack_str())
+        }
+        (Some(held_for), None) => {
+            format!("{} dropped the token (held for {})", user.as_slack_str(), clock::format_duration(held_for))
+        }
+        (None, _) => format!("{} left the queue", user.as_slack_str()),
+    }
+}
+
+/// Announce someone stealing the token, along with their reason. Fails if a reason is required
+/// but none was given.
+pub fn steal_message(user: &User, reason: Option<&str>, require_reason: bool) -> Result<String, &'static str> {
+    match reason {
+        Some(reason) if !reason.is_empty() => Ok(format!("{} stole the token: {}", user.as_slack_str(), reason)),
+        _ if require_reason => Err("Please provide a reason: `/token steal fixing prod outage`"),
+        _ => Ok(format!("{} stole the token!", user.as_slack_str())),
+    }
+}
+
+/// Post a notice to the channel, mentioning everyone in the queue so they see it
+pub fn format_announcement(admin: &User, text: &str, token: &Token) -> SlackResponse {
+    let mut announcement = format!(":mega: {} (from {})", text, admin.as_slack_str());
+    if token.len() > 0 {
+        let queued: Vec<String> = token.iter().map(|u| u.as_slack_str()).collect();
+        announcement = announcement + "\ncc " + &queued.join(", ");
+    }
+    SlackResponse::inchannel_text(&announcement)
+}
+
+/// Rank people by how long they have held the token for, as given by `Token::hold_leaderboard`
+pub fn format_hold_leaderboard(leaderboard: &[(&str, u64)]) -> SlackResponse {
+    if leaderboard.is_empty() {
+        return SlackResponse::ephemeral_text("No one has finished holding the token yet");
+    }
+    let lines: Vec<String> = leaderboard.iter()
+        .enumerate()
+        .map(|(index, &(user_id, total))| {
+            format!("{}. <@{}> {}", token::queue_number(index), user_id, clock::format_duration(total))
+        })
+        .collect();
+    SlackResponse::ephemeral_text(&format!("Longest total holds:\n{}", lines.join("\n")))
+}
+
+/// Summarise every queue for an admin
+pub fn format_global_stats(stats: &GlobalStats) -> SlackResponse {
+    let mut text = format!("{} queue(s), with {} people waiting", stats.queues, stats.waiting);
+    if let Some(((_, ref channel, ref name), held_for)) = stats.longest_held {
+        text.push_str(&format!("\nLongest held: `{}` in <#{}>, for {}",
+                               name,
+                               channel,
+                               clock::format_duration(held_for)));
+    }
+    if let Some(((_, ref channel), count)) = stats.busiest_channel {
+        text.push_str(&format!("\nBusiest channel: <#{}>, with {} queued", channel, count));
+    }
+    SlackResponse::ephemeral_text(&text)
+}
+
+/// Confirm the bot is reachable, which version is running, and whether queues are restored from
+/// a backup and requests have to be signed
+pub fn format_ping(persistence: bool, signing: bool) -> SlackResponse {
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    SlackResponse::ephemeral_text(&format!("pong (token v{}, persistence {}, request signing {})",
+                                           env!("CARGO_PKG_VERSION"),
+                                           on_off(persistence),
+                                           on_off(signing)))
+}
+
+/// Token errors are shown only to the person who caused them, in red
+impl From<TokenError> for SlackResponse {
+    fn from(error: TokenError) -> SlackResponse {
+        error_response(error.message())
+    }
+}
+
+/// An ephemeral message in red, saying why a command was refused
+pub fn error_response(text: String) -> SlackResponse {
+    SlackResponse {
+        response_type: Ephemeral,
+        text: None,
+        attachments: vec![SlackAttachment {
+                              text: text,
+                              color: Some("danger".to_string()),
+                          }],
+        blocks: vec![],
+        thread_ts: None,
+    }
+}
+
+/// Refuse a command, such as `barge`, which only makes sense for someone waiting, because the
+/// caller already holds the token
+pub fn already_holding(command: Commands) -> SlackResponse {
+    error_response(format!("You're already holding the token, so there's no need to {}. Use `/token drop` when \
+                            you're done.",
+                           command.name()))
+}
+
+/// Report just the current holder of the token
+pub fn format_holder(token: &Token) -> SlackResponse {
+    match token.front() {
+        Some(holder) => SlackResponse::ephemeral_text(&format!("{} is holding the token", holder.as_slack_str())),
+        None => SlackResponse::ephemeral_text("No one is holding the token"),
+    }
+}
+
+/// Tidy up command text before parsing it. Mobile clients can pad it with whitespace and swap
+/// quotes for curly ones, which would otherwise stop options such as mentions being recognised.
+pub fn normalize_text(text: &str) -> String {
+    text.trim()
+        .chars()
+        .map(|c| match c {
+            '\u{2018}' | '\u{2019}' => '\'',
+            '\u{201c}' | '\u{201d}' => '"',
+            c => c,
+        })
+        .collect()
+}
+
+/// Parse a user id from a Slack mention such as `<@U123|alice>`, or a bare user id
+pub fn parse_mention(text: &str) -> Option<UserId> {
+    let text = text.trim().trim_matches(|c| c == '"' || c == '\'');
+    let id = if text.starts_with("<@") && text.ends_with('>') {
+        text[2..text.len() - 1].split('|').next().unwrap_or("")
+    } else {
+        text
+    };
+    if id.is_empty() || id.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(id.to_owned())
+    }
+}
+
+/// Parse a user from a Slack mention, using their id as their name if the mention doesn't have one
+pub fn parse_user(text: &str) -> Option<User> {
+    let id = match parse_mention(text) {
+        Some(id) => id,
+        None => return None,
+    };
+    let text = text.trim();
+    let name = match text.find('|') {
+        Some(i) if text.starts_with("<@") => text[i + 1..text.len() - 1].to_owned(),
+        _ => id.clone(),
+    };
+    Some(User::new(id, name))
+}
+
+/// Parse the arguments `<@user> at <position>`, where positions are numbered from 1
+pub fn parse_user_at(text: &str) -> Option<(User, usize)> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() != 3 || parts[1] != "at" {
+        return None;
+    }
+    match (parse_user(parts[0]), parts[2].trim_left_matches('#').parse::<usize>()) {
+        (Some(user), Ok(position)) if position > 0 => Some((user, position)),
+        _ => None,
+    }
+}
+
+/// Report where the given user is in the queue, and how long they have been there
+pub fn format_whois(token: &Token, user_id: &str, now: Timestamp) -> SlackResponse {
+    match token.find_by_id(user_id) {
+        Some((0, user)) => {
+            let held_for = token.holder_since().map(|since| now.saturating_sub(since)).unwrap_or(0);
+            SlackResponse::ephemeral_text(&format!("{} is holding the token, and has been for {}",
+                                                   user.as_slack_str(),
+                                                   clock::format_duration(held_for)))
+        }
+        Some((pos, user)) => {
+            let waited_for = user.joined_at().map(|joined| now.saturating_sub(joined)).unwrap_or(0);
+            SlackResponse::ephemeral_text(&format!("{} is #{} in the queue, and has been waiting for {}",
+                                                   user.as_slack_str(),
+                                                   token::queue_number(pos),
+                                                   clock::format_duration(waited_for)))
+        }
+        None => SlackResponse::ephemeral_text(&format!("<@{}> is not in the queue", user_id)),
+    }
+}
+
+/// Tell the user where they are in the queue, with a rough idea of when their turn will come if
+/// there is enough history to guess
+pub fn format_position(token: &Token, user_id: &str, now: Timestamp) -> SlackResponse {
+    match token.find_by_id(user_id) {
+        // The holder is #1 in the list, but they aren't waiting, so don't give them a place in line
+        Some((0, _)) => SlackResponse::ephemeral_text("You're holding the token"),
+        Some((pos, _)) => {
+            let number = token::queue_number(pos);
+            let text = match token.estimate_wait(pos, now) {
+                Some(wait) if wait < 60 => format!("You're #{} in the queue, and should be up any minute", number),
+                Some(wait) => {
+                    format!("You're #{} in the queue, about {} until your turn",
+                            number,
+                            clock::format_duration(wait))
+                }
+                None => format!("You're #{} in the queue", number),
+            };
+            SlackResponse::ephemeral_text(&text)
+        }
+        None => TokenError::NotQueued.into(),
+    }
+}
+
+/// The parts of a Web API `users.info` response we use
+#[derive(Deserialize)]
+struct UsersInfo {
+    ok: bool,
+    #[serde(default)]
+    user: Option<UserInfo>,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    #[serde(default)]
+    profile: Option<UserProfile>,
+}
+
+#[derive(Deserialize)]
+struct UserProfile {
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    real_name: String,
+}
+
+/// Looks up people's display names with the Web API, as the `user_name` slash commands are sent
+/// can be a legacy username. Names are cached for as long as the bot runs.
+pub struct DisplayNames {
+    bot_token: String,
+    names: Mutex<HashMap<UserId, UserName>>,
+}
+
+impl DisplayNames {
+    pub fn new(bot_token: String) -> DisplayNames {
+        DisplayNames {
+            bot_token: bot_token,
+            names: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The user's display name, or `fallback` if Slack won't tell us
+    pub fn resolve_display_name(&self, api: &Notifier, user_id: &str, fallback: &str) -> UserName {
+        if let Some(name) = self.names.lock().ok().and_then(|names| names.get(user_id).cloned()) {
+            return name;
+        }
+        let url = format!("https://slack.com/api/users.info?user={}", user_id);
+        let info = api.get_json(&url, &self.bot_token)
+            .ok()
+            .and_then(|body| serde_json::from_str::<UsersInfo>(&body).ok());
+        let profile = match info {
+            Some(UsersInfo { ok: true, user: Some(UserInfo { profile: Some(profile) }) }) => profile,
+            _ => return fallback.to_owned(),
+        };
+        let name = if !profile.display_name.is_empty() {
+            profile.display_name
+        } else if !profile.real_name.is_empty() {
+            profile.real_name
+        } else {
+            return fallback.to_owned();
+        };
+        if let Ok(mut names) = self.names.lock() {
+            names.insert(user_id.to_owned(), name.clone());
+        }
+        name
     }
 }
 
+#[derive(Serialize)]
+struct Reaction<'a> {
+    channel: &'a str,
+    timestamp: &'a str,
+    name: &'a str,
+}
+
+/// React to the message `timestamp` in `channel` with the emoji `name`, eg `wave`
+pub fn add_reaction(api: &Notifier,
+                    bot_token: &str,
+                    channel: &str,
+                    timestamp: &str,
+                    name: &str)
+                    -> Result<(), String> {
+    let reaction = Reaction {
+        channel: channel,
+        timestamp: timestamp,
+        // Slack wants the name without the colons people usually type around it
+        name: name.trim_matches(':'),
+    };
+    let body = serde_json::to_string(&reaction).map_err(|e| e.to_string())?;
+    api.post_json_with_token("https://slack.com/api/reactions.add", bot_token, &body).map(|_| ())
+}
+
+#[derive(Serialize)]
+struct Ephemeral<'a> {
+    channel: &'a str,
+    user: &'a str,
+    text: &'a str,
+}
+
+/// Show `text` to just `user_id` in `channel`, eg to tell them something happened to them
+pub fn post_ephemeral(api: &Notifier, bot_token: &str, channel: &str, user_id: &str, text: &str) -> Result<(), String> {
+    let message = Ephemeral {
+        channel: channel,
+        user: user_id,
+        text: text,
+    };
+    let body = serde_json::to_string(&message).map_err(|e| e.to_string())?;
+    api.post_json_with_token("https://slack.com/api/chat.postEphemeral", bot_token, &body).map(|_| ())
+}
+
+/// The live message kept up to date by `watch`
+#[derive(Serialize)]
+struct WatchMessage<'a> {
+    channel: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<&'a str>,
+    text: String,
+    blocks: Vec<Block>,
+}
+
+/// The parts of a Web API `chat.postMessage` response we use
+#[derive(Deserialize)]
+struct PostedMessage {
+    ok: bool,
+    #[serde(default)]
+    ts: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// The `ts` of a successful Web API response, or why it failed, eg `not_in_channel`
+fn posted_ts(response: &str) -> Result<Option<String>, String> {
+    match serde_json::from_str::<PostedMessage>(response) {
+        Ok(PostedMessage { ok: true, ts, .. }) => Ok(ts),
+        Ok(PostedMessage { error: Some(error), .. }) => Err(error),
+        _ => Err(format!("unexpected response: {}", response)),
+    }
+}
+
+fn watch_message<'a>(channel: &'a str, ts: Option<&'a str>, token: &Token) -> WatchMessage<'a> {
+    let mut text = "*Live queue*".to_string();
+    if token.len() == 0 {
+        text.push_str("\nNo one in the queue");
+    }
+    for (index, user) in token.iter().enumerate() {
+        text.push_str(&format!("\n{}. {}", token::queue_number(index), list_entry(user)));
+    }
+    WatchMessage {
+        channel: channel,
+        ts: ts,
+        text: text.clone(),
+        blocks: vec![Block {
+                         kind: "section",
+                         text: Some(BlockText {
+                             kind: "mrkdwn",
+                             text: text,
+                         }),
+                         elements: vec![],
+                     }],
+    }
+}
+
+/// Post a message showing the queue to `channel`, for `update_watch` to keep current. Returns
+/// the message's timestamp.
+pub fn post_watch(api: &Notifier, bot_token: &str, channel: &str, token: &Token) -> Result<String, String> {
+    let body = serde_json::to_string(&watch_message(channel, None, token)).map_err(|e| e.to_string())?;
+    let response = api.post_json_with_token("https://slack.com/api/chat.postMessage", bot_token, &body)?;
+    match posted_ts(&response)? {
+        Some(ts) => Ok(ts),
+        None => Err(format!("unexpected response: {}", response)),
+    }
+}
+
+/// Show the queue as it is now in the message `ts` posted by `post_watch`
+pub fn update_watch(api: &Notifier, bot_token: &str, channel: &str, ts: &str, token: &Token) -> Result<(), String> {
+    let body = serde_json::to_string(&watch_message(channel, Some(ts), token)).map_err(|e| e.to_string())?;
+    let response = api.post_json_with_token("https://slack.com/api/chat.update", bot_token, &body)?;
+    posted_ts(&response).map(|_| ())
+}
+
 pub fn validate_command(command: &SlashCommandData) -> Result<(), &'static str> {
     if !valid_team(&command.team_id) {
         return Err("invalid team");
@@ -109,3 +949,449 @@ fn valid_team(_team: &str) -> bool {
 fn valid_command(_command: &str) -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commands::CommandText;
+    use notify::MockNotifier;
+    use test_utils;
+
+    #[test]
+    fn test_format_name_list() {
+        let t = test_utils::token_with_users(2);
+
+        let mentions = format_list(None, t.iter());
+        let names = format_name_list(None, t.list_user_name().into_iter(), None);
+        assert!(mentions != names);
+        assert_eq!(mentions.attachments[0].text,
+                   ":large_blue_circle: <@id0|name0>\n:large_blue_circle: <@id1|name1>\n");
+        assert_eq!(names.attachments[0].text,
+                   ":large_blue_circle: name0\n:large_blue_circle: name1\n");
+    }
+
+    #[test]
+    fn test_format_hold_leaderboard() {
+        assert_eq!(format_hold_leaderboard(&[]).text.unwrap(), "No one has finished holding the token yet");
+        assert_eq!(format_hold_leaderboard(&[("id1", 2 * 3600 + 5 * 60), ("id0", 90)]).text.unwrap(),
+                   "Longest total holds:\n1. <@id1> 2h 5m\n2. <@id0> 1m");
+    }
+
+    #[test]
+    fn test_format_global_stats() {
+        let mut stats = GlobalStats {
+            queues: 3,
+            waiting: 2,
+            longest_held: None,
+            busiest_channel: None,
+        };
+        assert_eq!(format_global_stats(&stats).text.unwrap(), "3 queue(s), with 2 people waiting");
+
+        stats.longest_held = Some((("T1".to_string(), "C1".to_string(), "prod".to_string()), 120));
+        stats.busiest_channel = Some((("T1".to_string(), "C2".to_string()), 4));
+        assert_eq!(format_global_stats(&stats).text.unwrap(),
+                   "3 queue(s), with 2 people waiting\nLongest held: `prod` in <#C1>, for 2m\n\
+                    Busiest channel: <#C2>, with 4 queued");
+    }
+
+    #[test]
+    fn test_already_holding() {
+        let response = already_holding(Commands::Barge);
+        assert!(response.is_error());
+        assert_eq!(response.attachments[0].text,
+                   "You're already holding the token, so there's no need to barge. Use `/token drop` when you're \
+                    done.");
+    }
+
+    #[test]
+    fn test_format_list_notes() {
+        let mut t = Token::new();
+        let mut u0 = User::new("id0".to_string(), "name0".to_string());
+        u0.set_note(Some("hotfix for JIRA-123".to_string()));
+        t.get(u0).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+
+        assert_eq!(format_list(None, t.iter()).attachments[0].text,
+                   ":large_blue_circle: <@id0|name0> \u{2014} hotfix for JIRA-123\n\
+                    :large_blue_circle: <@id1|name1>\n");
+    }
+
+    #[test]
+    fn test_watch() {
+        let api = MockNotifier::new();
+        api.respond("https://slack.com/api/chat.postMessage", "{\"ok\": true, \"ts\": \"1500000000.000100\"}");
+        let mut t = Token::new();
+        assert_eq!(post_watch(&api, "xoxb", "C1", &t), Ok("1500000000.000100".to_string()));
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        update_watch(&api, "xoxb", "C1", "1500000000.000100", &t).unwrap();
+
+        let posts = api.posts();
+        assert_eq!(posts[0].0, "https://slack.com/api/chat.postMessage");
+        assert!(posts[0].1.starts_with("{\"channel\":\"C1\",\"text\":\"*Live queue*\\nNo one in the queue\""));
+        assert_eq!(posts[1].0, "https://slack.com/api/chat.update");
+        assert!(posts[1].1.starts_with("{\"channel\":\"C1\",\"ts\":\"1500000000.000100\",\
+                                         \"text\":\"*Live queue*\\n1. <@id0|name0>\""));
+        assert!(posts[1].1.contains("\"blocks\":[{\"type\":\"section\""));
+
+        api.respond("https://slack.com/api/chat.postMessage", "{\"ok\": false, \"error\": \"not_in_channel\"}");
+        assert_eq!(post_watch(&api, "xoxb", "C1", &t), Err("not_in_channel".to_string()));
+        api.respond("https://slack.com/api/chat.update", "{\"ok\": false, \"error\": \"message_not_found\"}");
+        assert_eq!(update_watch(&api, "xoxb", "C1", "1500000000.000100", &t),
+                   Err("message_not_found".to_string()));
+    }
+
+    #[test]
+    fn test_format_list_large() {
+        let t = test_utils::token_with_users(2000);
+        let response = format_list(None, t.iter());
+        let expected = t.iter().fold(String::new(), |acc, u| acc + ":large_blue_circle: " + &list_entry(u) + "\n");
+        assert_eq!(response.attachments[0].text, expected);
+        assert!(expected.starts_with(":large_blue_circle: <@id0|name0>\n"));
+        assert!(expected.ends_with(":large_blue_circle: <@id1999|name1999>\n"));
+    }
+
+    #[test]
+    fn test_truncate_name() {
+        assert_eq!(truncate_name("alice", None), "alice");
+        assert_eq!(truncate_name("alice", Some(5)), "alice");
+        assert_eq!(truncate_name("alice", Some(4)), "ali\u{2026}");
+        assert_eq!(truncate_name("\u{e9}\u{e9}\u{e9}", Some(2)), "\u{e9}\u{2026}");
+
+        let mut t = Token::new();
+        t.get(User::new("id0".to_string(), "bartholomew".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "bob".to_string())).unwrap();
+        let names = format_name_list(None, t.list_user_name().into_iter(), Some(6));
+        assert_eq!(names.attachments[0].text,
+                   ":large_blue_circle: barth\u{2026}\n:large_blue_circle: bob\n");
+    }
+
+    #[test]
+    fn test_parse_mention() {
+        assert_eq!(parse_mention("<@U123|alice>"), Some("U123".to_string()));
+        assert_eq!(parse_mention("<@U123>"), Some("U123".to_string()));
+        assert_eq!(parse_mention(" U123 "), Some("U123".to_string()));
+        assert_eq!(parse_mention(""), None);
+        assert_eq!(parse_mention("<@>"), None);
+    }
+
+    #[test]
+    fn test_form_round_trip() {
+        let command = SlashCommandData::from_user("U2", "get <@U1|alice> at #2 & 100%");
+        let form = command.to_form();
+        let parsed = SlashCommandData::from_form_items(&mut FormItems::from(form.as_str())).unwrap();
+        assert_eq!(parsed.user_id, "U2");
+        assert_eq!(parsed.user_name, "u2");
+        assert_eq!(parsed.text, "get <@U1|alice> at #2 & 100%");
+        assert_eq!(parsed.command, "/token");
+    }
+
+    #[test]
+    fn test_resolve_display_name() {
+        let api = MockNotifier::new();
+        api.respond("https://slack.com/api/users.info?user=U1",
+                    "{\"ok\": true, \"user\": {\"id\": \"U1\", \"name\": \"asmith\", \
+                     \"profile\": {\"display_name\": \"Alice\", \"real_name\": \"Alice Smith\"}}}");
+        api.respond("https://slack.com/api/users.info?user=U2",
+                    "{\"ok\": false, \"error\": \"user_not_found\"}");
+        let names = DisplayNames::new("xoxb-test".to_string());
+
+        assert_eq!(names.resolve_display_name(&api, "U1", "asmith"), "Alice");
+        assert_eq!(names.resolve_display_name(&api, "U1", "asmith"), "Alice");
+        assert_eq!(names.resolve_display_name(&api, "U2", "bob"), "bob");
+        assert_eq!(names.resolve_display_name(&api, "U3", "carol"), "carol");
+        // The first lookup was cached
+        assert_eq!(api.gets().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_interaction() {
+        let command = parse_interaction("{\"type\": \"block_actions\", \"token\": \"secret\", \
+                                         \"team\": {\"id\": \"T1\", \"domain\": \"example\"}, \
+                                         \"channel\": {\"id\": \"C1\", \"name\": \"general\"}, \
+                                         \"user\": {\"id\": \"U1\", \"username\": \"alice\", \"team_id\": \"T1\"}, \
+                                         \"response_url\": \"https://hooks.slack.com/actions/1\", \
+                                         \"actions\": [{\"action_id\": \"get\", \"value\": \"get\", \
+                                         \"type\": \"button\"}]}")
+            .unwrap();
+        assert_eq!(command.text, "get");
+        assert_eq!(command.user_id, "U1");
+        assert_eq!(command.user_name, "alice");
+        assert_eq!(command.channel_id, "C1");
+        assert_eq!(command.response_url, "https://hooks.slack.com/actions/1");
+
+        assert!(parse_interaction("{\"type\": \"block_actions\"}").is_err());
+    }
+
+    #[test]
+    fn test_home() {
+        let json = serde_json::to_string(&home()).unwrap();
+        assert!(json.contains("\"type\":\"actions\""));
+        for command in &["get", "drop", "list"] {
+            assert!(json.contains(&format!("\"value\":\"{}\"", command)));
+        }
+        // Responses without blocks don't mention them
+        assert!(!serde_json::to_string(&format_ping(false, false)).unwrap().contains("blocks"));
+    }
+
+    #[test]
+    fn test_normalize_text() {
+        assert_eq!(normalize_text("\u{a0} announce \u{201c}deploy\u{201d} isn\u{2019}t done\n"),
+                   "announce \"deploy\" isn't done");
+
+        let normalized = normalize_text("  whois \u{201c}<@U1|alice>\u{201d} ");
+        let text = CommandText::parse(&normalized, true);
+        assert_eq!(text.command, Some(Commands::Whois));
+        assert_eq!(text.options.and_then(parse_mention), Some("U1".to_string()));
+    }
+
+    #[test]
+    fn test_parse_user_at() {
+        let (user, position) = parse_user_at("<@U1|alice> at 3").unwrap();
+        assert_eq!((user.id(), user.name(), position), ("U1", "alice", 3));
+        let (user, position) = parse_user_at("<@U1> at #2").unwrap();
+        assert_eq!((user.id(), user.name(), position), ("U1", "U1", 2));
+        assert!(parse_user_at("<@U1> at 0").is_none());
+        assert!(parse_user_at("<@U1> 3").is_none());
+        assert!(parse_user_at("fixing prod").is_none());
+    }
+
+    #[test]
+    fn test_format_whois() {
+        let mut t = Token::new();
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        t.get(User::new("id2".to_string(), "name2".to_string())).unwrap();
+        let joined = t.find_by_id("id2").unwrap().1.joined_at().unwrap();
+
+        assert_eq!(format_whois(&t, "id2", joined + 12 * 60),
+                   SlackResponse::ephemeral_text("<@id2|name2> is #3 in the queue, and has been waiting for 12m"));
+        assert_eq!(format_whois(&t, "id3", joined),
+                   SlackResponse::ephemeral_text("<@id3> is not in the queue"));
+    }
+
+    #[test]
+    fn test_format_position() {
+        let mut t = Token::new();
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        let now = clock::now();
+
+        assert_eq!(format_position(&t, "id0", now), SlackResponse::ephemeral_text("You're holding the token"));
+        // No one has finished holding the token yet, so there's nothing to estimate from
+        assert_eq!(format_position(&t, "id1", now), SlackResponse::ephemeral_text("You're #2 in the queue"));
+        assert_eq!(format_position(&t, "id2", now), TokenError::NotQueued.into());
+    }
+
+    #[test]
+    fn test_format_position_holder() {
+        let mut t = Token::new();
+        for i in 0..3 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        let now = clock::now();
+
+        // Whoever reaches the front is told they hold the token, never given a number
+        for i in 0..3 {
+            let holder = format!("id{}", i);
+            let text = format_position(&t, &holder, now).text.unwrap();
+            assert_eq!(text, "You're holding the token");
+            assert!(!text.contains('#'));
+            t.drop(&User::new(holder, format!("name{}", i))).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_join_message() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+
+        let index = t.get(u0.clone()).unwrap();
+        assert_eq!(join_message(&u0, index, t.front()),
+                   "<@id0|name0> joined the queue and is holding the token");
+        let index = t.get(u1.clone()).unwrap();
+        assert_eq!(join_message(&u1, index, t.front()),
+                   "<@id1|name1> joined the queue, you're #2 with 1 person ahead of you and <@id0|name0> is \
+                    holding the token");
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        let index = t.get(u2.clone()).unwrap();
+        assert_eq!(join_message(&u2, index, t.front()),
+                   "<@id2|name2> joined the queue, you're #3 with 2 people ahead of you and <@id0|name0> is \
+                    holding the token");
+    }
+
+    #[test]
+    fn test_drop_message() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+
+        t.drop(&u1).unwrap();
+        assert_eq!(drop_message(&u1, None, t.front()), "<@id1|name1> left the queue");
+
+        t.drop(&u0).unwrap();
+        assert_eq!(drop_message(&u0, Some(12 * 60), t.front()),
+                   "<@id0|name0> dropped the token (held for 12m), <@id2|name2> is up");
+
+        t.drop(&u2).unwrap();
+        assert_eq!(drop_message(&u2, Some(30), t.front()),
+                   "<@id2|name2> dropped the token (held for 30s)");
+    }
+
+    #[test]
+    fn test_steal_message() {
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        assert_eq!(steal_message(&u0, None, true),
+                   Err("Please provide a reason: `/token steal fixing prod outage`"));
+        assert_eq!(steal_message(&u0, Some(""), true),
+                   Err("Please provide a reason: `/token steal fixing prod outage`"));
+        assert_eq!(steal_message(&u0, Some("fixing prod outage"), true),
+                   Ok("<@id0|name0> stole the token: fixing prod outage".to_string()));
+        assert_eq!(steal_message(&u0, None, false), Ok("<@id0|name0> stole the token!".to_string()));
+    }
+
+    #[test]
+    fn test_long_queue_warning() {
+        let warning = SlackAttachment::new(":warning: This queue is getting long, is the resource stuck?".to_string());
+        let response = SlackResponse::inchannel_text("list").with_long_queue_warning(5, Some(5));
+        assert!(!response.attachments.contains(&warning));
+        let response = SlackResponse::inchannel_text("list").with_long_queue_warning(6, Some(5));
+        assert!(response.attachments.contains(&warning));
+        let response = SlackResponse::inchannel_text("list").with_long_queue_warning(100, None);
+        assert!(!response.attachments.contains(&warning));
+    }
+
+    #[test]
+    fn test_help() {
+        let help = send_command_help(Commands::Steal);
+        assert!(help.attachments[0].text.contains("`/token steal`"));
+        assert!(help.attachments[0].text.contains("/token steal fixing prod outage"));
+        assert!(!help.attachments[0].text.contains("`/token get`"));
+
+        let help = send_help(true, true);
+        for info in Commands::all() {
+            assert!(help.attachments[0].text.contains(&format!("`/token {}`", info.name)));
+        }
+    }
+
+    #[test]
+    fn test_help_examples() {
+        let help = send_help_examples();
+        for command in &["get", "list", "drop", "steal"] {
+            assert!(help.attachments[0].text.contains(&format!("`/token {}", command)), "{}", command);
+        }
+        assert!(help.attachments[0].text.contains("`/token get` \u{2014} join the queue"));
+    }
+
+    #[test]
+    fn test_help_for_non_admins() {
+        let help = send_help(false, false);
+        for info in Commands::all() {
+            let listed = help.attachments[0].text.contains(&format!("`/token {}`", info.name));
+            assert_eq!(listed, !info.admin_only, "{}", info.name);
+        }
+        assert!(help.attachments[0].text.contains("`/token get`"));
+        assert!(!help.attachments[0].text.contains("`/token freeze`"));
+    }
+
+    #[test]
+    fn test_help_for_super_admins() {
+        // Ordinary admins aren't told about commands they can't run
+        let help = send_help(true, false);
+        assert!(help.attachments[0].text.contains("`/token freeze`"));
+        assert!(!help.attachments[0].text.contains("`/token purge`"));
+
+        let help = send_help(false, true);
+        assert!(help.attachments[0].text.contains("`/token purge`"));
+        assert!(!help.attachments[0].text.contains("`/token freeze`"));
+    }
+
+    #[test]
+    fn test_format_announcement() {
+        let mut t = Token::new();
+        let admin = User::new("admin".to_string(), "admin".to_string());
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        let before = t.clone();
+
+        let response = format_announcement(&admin, "prod is down for 30m", &t);
+        assert_eq!(response.response_type, InChannel);
+        assert_eq!(response.text.unwrap(),
+                   ":mega: prod is down for 30m (from <@admin|admin>)\ncc <@id0|name0>, <@id1|name1>");
+        assert_eq!(t, before);
+    }
+
+    #[test]
+    fn test_from_token_error() {
+        let response: SlackResponse = TokenError::AlreadyWaiting(2).into();
+        assert_eq!(response,
+                   SlackResponse {
+                       response_type: Ephemeral,
+                       text: None,
+                       attachments: vec![SlackAttachment {
+                                             text: "You are already waiting at #3!".to_string(),
+                                             color: Some("danger".to_string()),
+                                         }],
+                       blocks: vec![],
+                       thread_ts: None,
+                   });
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        // The example from Slack's documentation
+        let secret = "8f742231b10e8888abcd99yyyzzz85a5";
+        let body = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&\
+                    channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&\
+                    command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2F\
+                    commands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&\
+                    trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+        let signed = SlackSignature {
+            signature: Some("v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503".to_string()),
+            timestamp: Some("1531420618".to_string()),
+        };
+        let now = 1531420618 + 60;
+        assert_eq!(signed.verify(secret, body, now),
+                   Ok("v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503"));
+
+        assert_eq!(signed.verify("wrong", body, now), Err("invalid signature"));
+        assert_eq!(signed.verify(secret, "token=other", now), Err("invalid signature"));
+        assert_eq!(signed.verify(secret, body, 1531420618 + MAX_SIGNATURE_AGE_SECS + 1),
+                   Err("stale request timestamp"));
+        assert_eq!(signed.verify(secret, body, 1531420618 - MAX_SIGNATURE_AGE_SECS - 1),
+                   Err("stale request timestamp"));
+
+        let unsigned = SlackSignature {
+            signature: None,
+            timestamp: Some("1531420618".to_string()),
+        };
+        assert_eq!(unsigned.verify(secret, body, now), Err("missing signature"));
+    }
+
+    #[test]
+    fn test_format_ping() {
+        let text = format_ping(false, false).text.unwrap();
+        assert!(text.contains("pong"));
+        assert!(text.contains(env!("CARGO_PKG_VERSION")));
+        assert!(text.contains("persistence off, request signing off"));
+        let text = format_ping(true, false).text.unwrap();
+        assert!(text.contains("persistence on, request signing off"));
+        let text = format_ping(false, true).text.unwrap();
+        assert!(text.contains("persistence off, request signing on"));
+    }
+
+    #[test]
+    fn test_format_holder() {
+        let mut t = Token::new();
+        assert_eq!(format_holder(&t), SlackResponse::ephemeral_text("No one is holding the token"));
+
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        assert_eq!(format_holder(&t),
+                   SlackResponse::ephemeral_text("<@id0|name0> is holding the token"));
+    }
+}
@@ -0,0 +1,21 @@
+//! Shared setup for tests, so each one doesn't have to build its own queue by hand.
+use token::{Token, User};
+
+/// The user numbered `i`, with the id `id{i}` and the name `name{i}`
+pub fn user(i: usize) -> User {
+    User::new(format!("id{}", i), format!("name{}", i))
+}
+
+/// The users numbered 0 to `n - 1`, in order
+pub fn users(n: usize) -> Vec<User> {
+    (0..n).map(user).collect()
+}
+
+/// A token queueing `users(n)`, so user 0 is holding it
+pub fn token_with_users(n: usize) -> Token {
+    let mut token = Token::new();
+    for u in users(n) {
+        token.get(u).unwrap();
+    }
+    token
+}
@@ -1,29 +1,131 @@
 //! A `Token` tracks the current "owner" of something, and is unique per channel.
 #![allow(dead_code)]
 
+use std::cmp;
 use std::collections::{HashMap, VecDeque};
+use std::collections::vec_deque;
 use std::sync::{Arc, Mutex, RwLock};
 use std::fmt;
 
+use serde_json;
+
+use clock::{self, Timestamp};
 use slack::{TeamId, ChannelId};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     user_id: String,
     user_name: String,
+    /// When the user joined the queue
+    #[serde(default)]
+    joined_at: Option<Timestamp>,
+    /// Rejoin the back of the queue after releasing the token
+    #[serde(default)]
+    sticky: bool,
+    /// Mention the user when they reach the front of the queue
+    #[serde(default)]
+    notify_on_front: bool,
+    /// Why the user is in the queue, eg "hotfix for JIRA-123"
+    #[serde(default)]
+    note: Option<String>,
+    /// Whether the user joined ahead of normal users, as the queue's `PriorityPolicy` allows
+    #[serde(default)]
+    priority: bool,
+    /// How many priority users have joined ahead of this user while they waited
+    #[serde(default)]
+    overtaken: usize,
+}
+
+/// Users are identified by their id alone, as their name can change and the other fields describe
+/// their place in a queue rather than who they are.
+impl PartialEq for User {
+    fn eq(&self, other: &User) -> bool {
+        self.user_id == other.user_id
+    }
 }
 
+impl Eq for User {}
+
 impl User {
+    /// A user from untrusted input, such as a request, which must have an id
+    pub fn try_new(user_id: String, user_name: String) -> Result<User, TokenError> {
+        if user_id.trim().is_empty() {
+            return Err(TokenError::MissingId);
+        }
+        Ok(User::new(user_id, user_name))
+    }
+
     pub fn new(user_id: String, user_name: String) -> User {
         User {
             user_id: user_id,
             user_name: user_name,
+            joined_at: None,
+            sticky: false,
+            notify_on_front: false,
+            note: None,
+            priority: false,
+            overtaken: 0,
         }
     }
 
+    pub fn id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.user_name
+    }
+
+    pub fn joined_at(&self) -> Option<Timestamp> {
+        self.joined_at
+    }
+
+    pub fn is_sticky(&self) -> bool {
+        self.sticky
+    }
+
+    pub fn set_sticky(&mut self, sticky: bool) {
+        self.sticky = sticky;
+    }
+
+    pub fn notify_on_front(&self) -> bool {
+        self.notify_on_front
+    }
+
+    pub fn set_notify_on_front(&mut self, notify_on_front: bool) {
+        self.notify_on_front = notify_on_front;
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_ref().map(|n| n.as_str())
+    }
+
+    pub fn set_note(&mut self, note: Option<String>) {
+        self.note = note;
+    }
+
+    pub fn is_priority(&self) -> bool {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: bool) {
+        self.priority = priority;
+    }
+
+    /// The full mention, `<@id|name>`, which notifies the user and shows their current name
     pub fn as_slack_str(&self) -> String {
         format!("<@{}|{}>", self.user_id, self.user_name)
     }
+
+    /// A mention by id alone, `<@id>`, for when the name may be out of date
+    pub fn mention_only(&self) -> String {
+        format!("<@{}>", self.user_id)
+    }
+
+    /// The plain display name, which doesn't notify the user, eg for logs
+    pub fn name_only(&self) -> String {
+        self.user_name.clone()
+    }
 }
 
 impl fmt::Display for User {
@@ -33,18 +135,417 @@ impl fmt::Display for User {
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
+/// Where priority users join the queue
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum PriorityPolicy {
+    /// Ahead of every waiting normal user
+    JumpAhead,
+    /// Ahead of normal users, but no normal user can be passed by more than this many, so they
+    /// still get a turn
+    Interleave(usize),
+}
+
+impl Default for PriorityPolicy {
+    fn default() -> PriorityPolicy {
+        PriorityPolicy::JumpAhead
+    }
+}
+
+/// Where someone who tried to barge ended up
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Barged {
+    ToFront,
+    /// They had barged too often, and were sent to the back instead
+    ToBack,
+}
+
+/// The ways a change to a token's queue can be refused
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TokenError {
+    /// Already waiting at the given index
+    AlreadyWaiting(usize),
+    OtherAlreadyQueued,
+    NotQueued,
+    OtherNotQueued,
+    NotHolding,
+    AlreadyHolding,
+    AlreadyNext,
+    AtEnd,
+    QueueFull,
+    Frozen,
+    SamePosition,
+    NoSuchPosition,
+    WaitlistFull,
+    /// A user had no id to tell them apart from anyone else
+    MissingId,
+    /// A new order for the queue didn't list exactly the people already in it
+    OrderMismatch,
+    /// The user left the queue too recently to rejoin for this many more seconds
+    RejoinCooldown(u64),
+    /// The holder only just took the token, and can't be stolen from for this many more seconds
+    InGrace(u64),
+}
+
+impl TokenError {
+    pub fn message(&self) -> String {
+        let message = match *self {
+            TokenError::AlreadyWaiting(index) => {
+                return format!("You are already waiting at #{}!", queue_number(index))
+            }
+            TokenError::RejoinCooldown(remaining) => {
+                return format!("You only just left the queue, you can rejoin in {}",
+                               clock::format_duration(remaining))
+            }
+            TokenError::InGrace(remaining) => {
+                return format!("The holder only just got the token, you can steal it in {}",
+                               clock::format_duration(remaining))
+            }
+            TokenError::OtherAlreadyQueued => "They are already in the queue!",
+            TokenError::NotQueued => "You are not in the queue!",
+            TokenError::OtherNotQueued => "They are not in the queue!",
+            TokenError::NotHolding => "You are not holding the token!",
+            TokenError::AlreadyHolding => "You are already holding the token!",
+            TokenError::AlreadyNext => "You are already next in the queue!",
+            TokenError::AtEnd => "You are at the end of the queue!",
+            TokenError::QueueFull => "The queue is full!",
+            TokenError::Frozen => "The queue is frozen!",
+            TokenError::SamePosition => "Those are the same position!",
+            TokenError::NoSuchPosition => "There is no one at that position!",
+            TokenError::WaitlistFull => "The waitlist is full!",
+            TokenError::MissingId => "Slack didn't say who you are!",
+            TokenError::OrderMismatch => "The new order must list everyone in the queue, exactly once!",
+        };
+        message.to_string()
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Token {
     users: VecDeque<User>,
+    /// When the current holder took the token
+    #[serde(default)]
+    holder_since: Option<Timestamp>,
+    /// When the current holder was last reminded that they are holding the token
+    #[serde(skip_serializing, skip_deserializing)]
+    last_reminded: Option<Timestamp>,
+    /// The most recent `response_url` Slack gave us for this token's channel
+    #[serde(skip_serializing, skip_deserializing)]
+    response_url: Option<String>,
+    /// The most users allowed in the queue, including the holder
+    #[serde(default)]
+    max_size: Option<usize>,
+    /// Whether the queue is locked, so no one can join, leave or move
+    #[serde(default)]
+    frozen: bool,
+    /// What the token is for, eg "prod deploy lock"
+    #[serde(default)]
+    description: Option<String>,
+    /// When each user recently barged, for limiting how often they can
+    #[serde(skip_serializing, skip_deserializing)]
+    barges: HashMap<String, Vec<Timestamp>>,
+    /// When each user last left the queue, for limiting how soon they can rejoin
+    #[serde(skip_serializing, skip_deserializing)]
+    drops: HashMap<String, Timestamp>,
+    /// A new holder who asked to be told when they reached the front, and hasn't been yet
+    #[serde(skip_serializing, skip_deserializing)]
+    front_ping: Option<User>,
+    /// How long recent holders held the token for, oldest first, for estimating waits
+    #[serde(skip_serializing, skip_deserializing)]
+    hold_times: VecDeque<u64>,
+    /// How long each user has held the token for in total, by id
+    #[serde(skip_serializing, skip_deserializing)]
+    hold_totals: HashMap<String, u64>,
+    /// The timestamp of the message `watch` keeps showing the queue in
+    #[serde(skip_serializing, skip_deserializing)]
+    watch_ts: Option<String>,
+    /// How many seconds each user who opted in to auto-drop wants to hold the token for, by id
+    #[serde(default)]
+    auto_drop: HashMap<String, u64>,
+    /// Where priority users join the queue
+    #[serde(default)]
+    priority_policy: PriorityPolicy,
 }
+
+/// The number people see for a queue index. Indexes start from 0 at the holder, but people count
+/// from #1, so every message should go through this.
+pub fn queue_number(index: usize) -> usize {
+    index + 1
+}
+
+/// The queue index for a number someone typed, or `None` for #0
+pub fn queue_index(number: usize) -> Option<usize> {
+    number.checked_sub(1)
+}
+
+/// How many recent hold times are kept for estimating waits
+const HOLD_TIMES: usize = 10;
+
 pub type TokenRef = Arc<RwLock<Token>>;
-pub type TokensType = Mutex<HashMap<(TeamId, ChannelId), TokenRef>>;
+pub type TokenName = String;
+pub type TokensType = Mutex<HashMap<(TeamId, ChannelId, TokenName), TokenRef>>;
 
-pub struct Tokens(pub TokensType);
+/// A copy of a token, along with where it lives, for backups
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub team_id: TeamId,
+    pub channel_id: ChannelId,
+    pub name: TokenName,
+    pub token: Token,
+}
+
+/// An overview of every queue, for admins
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct GlobalStats {
+    /// How many queues there are, in every channel
+    pub queues: usize,
+    /// How many people are waiting behind a holder, in every queue
+    pub waiting: usize,
+    /// The token which has had the same holder for longest, and for how many seconds
+    pub longest_held: Option<((TeamId, ChannelId, TokenName), u64)>,
+    /// The channel with the most people queued across its tokens, and how many
+    pub busiest_channel: Option<((TeamId, ChannelId), usize)>,
+}
+
+/// The name of a channel's token when none is given
+pub const DEFAULT_TOKEN: &'static str = "default";
+
+/// The last serialized snapshot of every token, along with what it was serialized from
+type SnapshotCache = Mutex<Option<(Vec<QueueSnapshot>, String)>>;
+
+#[derive(Clone)]
+pub struct Tokens(pub Arc<TokensType>, Arc<SnapshotCache>);
 
 impl Tokens {
     pub fn new() -> Tokens {
-        Tokens(Mutex::new(HashMap::new()))
+        Tokens(Arc::new(Mutex::new(HashMap::new())), Arc::new(Mutex::new(None)))
+    }
+
+    /// Get a channel's named token, creating an empty one if it doesn't exist yet
+    pub fn get_or_create(&self, team: &str, channel: &str, name: &str) -> TokenRef {
+        self.get_or_create_within(team, channel, name, None).unwrap()
+    }
+
+    /// As `get_or_create`, but refuse to create a new named token if the channel already has
+    /// `limit` of them, in which case the names of the existing ones are returned. The default
+    /// token is always allowed.
+    pub fn get_or_create_within(&self,
+                                team: &str,
+                                channel: &str,
+                                name: &str,
+                                limit: Option<usize>)
+                                -> Result<TokenRef, Vec<TokenName>> {
+        let mut tokens_map = self.0.lock().unwrap();
+        let key = (team.to_owned(), channel.to_owned(), name.to_owned());
+        if let Some(token) = tokens_map.get(&key) {
+            return Ok(token.clone());
+        }
+        if let (Some(limit), true) = (limit, name != DEFAULT_TOKEN) {
+            let mut existing: Vec<TokenName> = tokens_map.keys()
+                .filter(|&&(ref t, ref c, ref n)| t == team && c == channel && n != DEFAULT_TOKEN)
+                .map(|&(_, _, ref n)| n.clone())
+                .collect();
+            if existing.len() >= limit {
+                existing.sort();
+                return Err(existing);
+            }
+        }
+        let token = Arc::new(RwLock::new(Token::new()));
+        tokens_map.insert(key, token.clone());
+        Ok(token)
+    }
+
+    /// All of a channel's tokens, sorted by name
+    pub fn for_channel(&self, team: &str, channel: &str) -> Vec<(TokenName, TokenRef)> {
+        let tokens_map = self.0.lock().unwrap();
+        let mut tokens: Vec<(TokenName, TokenRef)> = tokens_map.iter()
+            .filter(|&(&(ref t, ref c, _), _)| t == team && c == channel)
+            .map(|(&(_, _, ref name), token)| (name.clone(), token.clone()))
+            .collect();
+        tokens.sort_by(|a, b| a.0.cmp(&b.0));
+        tokens
+    }
+
+    /// Copy all of a channel's tokens
+    pub fn snapshot_channel(&self, team: &str, channel: &str) -> Vec<QueueSnapshot> {
+        self.for_channel(team, channel)
+            .into_iter()
+            .filter_map(|(name, token)| {
+                let snapshot = token.read().ok().map(|token| {
+                    QueueSnapshot {
+                        team_id: team.to_owned(),
+                        channel_id: channel.to_owned(),
+                        name: name,
+                        token: token.clone(),
+                    }
+                });
+                snapshot
+            })
+            .collect()
+    }
+
+    /// Rename one of a channel's tokens, keeping its queue
+    pub fn rename(&self, team: &str, channel: &str, old: &str, new: &str) -> Result<(), &'static str> {
+        let mut tokens_map = self.0.lock().unwrap();
+        let new_key = (team.to_owned(), channel.to_owned(), new.to_owned());
+        if tokens_map.contains_key(&new_key) {
+            return Err("A token with that name already exists!");
+        }
+        match tokens_map.remove(&(team.to_owned(), channel.to_owned(), old.to_owned())) {
+            Some(token) => {
+                tokens_map.insert(new_key, token);
+                Ok(())
+            }
+            None => Err("There is no token with that name!"),
+        }
+    }
+
+    /// Visit every token, read-locking each in turn while the map is held. Tokens are visited in
+    /// key order so that everything walking the map takes its locks in the same order; `f` must
+    /// not lock the map or any token itself.
+    pub fn for_each<F>(&self, mut f: F)
+        where F: FnMut(&(TeamId, ChannelId, TokenName), &Token)
+    {
+        let tokens_map = match self.0.lock() {
+            Ok(tokens_map) => tokens_map,
+            Err(_) => return,
+        };
+        let mut keys: Vec<&(TeamId, ChannelId, TokenName)> = tokens_map.keys().collect();
+        keys.sort();
+        for key in keys {
+            if let Ok(token) = tokens_map[key].read() {
+                f(key, &token);
+            }
+        }
+    }
+
+    /// Add up every queue into a `GlobalStats`
+    pub fn global_stats(&self, now: Timestamp) -> GlobalStats {
+        let mut stats = GlobalStats::default();
+        let mut channels: Vec<((TeamId, ChannelId), usize)> = Vec::new();
+        self.for_each(|key, token| {
+            stats.queues += 1;
+            stats.waiting += token.len_excluding_holder();
+            if let Some(since) = token.holder_since() {
+                let held_for = now.saturating_sub(since);
+                if stats.longest_held.as_ref().map_or(true, |&(_, longest)| held_for > longest) {
+                    stats.longest_held = Some((key.clone(), held_for));
+                }
+            }
+            let channel = (key.0.clone(), key.1.clone());
+            // Keys are visited in order, so each channel's tokens are visited together
+            if channels.last().map_or(false, |&(ref last, _)| *last == channel) {
+                channels.last_mut().unwrap().1 += token.len();
+            } else {
+                channels.push((channel, token.len()));
+            }
+        });
+        for (channel, count) in channels {
+            if count > 0 && stats.busiest_channel.as_ref().map_or(true, |&(_, busiest)| count > busiest) {
+                stats.busiest_channel = Some((channel, count));
+            }
+        }
+        stats
+    }
+
+    /// A copy of every token, in key order
+    pub fn snapshot(&self) -> Vec<QueueSnapshot> {
+        let mut snapshots = Vec::new();
+        self.for_each(|&(ref team, ref channel, ref name), token| {
+            snapshots.push(QueueSnapshot {
+                team_id: team.clone(),
+                channel_id: channel.clone(),
+                name: name.clone(),
+                token: token.clone(),
+            })
+        });
+        snapshots
+    }
+
+    /// Replace every token with those in `snapshots`, eg from a backup, returning how many there
+    /// were. Nothing is changed if the same token appears twice.
+    pub fn import_snapshot(&self, snapshots: Vec<QueueSnapshot>) -> Result<usize, String> {
+        let mut imported = HashMap::new();
+        for snapshot in snapshots {
+            let key = (snapshot.team_id, snapshot.channel_id, snapshot.name);
+            if imported.contains_key(&key) {
+                return Err(format!("`{}/{}/{}` appears more than once", key.0, key.1, key.2));
+            }
+            imported.insert(key, Arc::new(RwLock::new(snapshot.token)));
+        }
+        let count = imported.len();
+        *self.0.lock().map_err(|_| "unable to lock the tokens".to_string())? = imported;
+        Ok(count)
+    }
+
+    /// Every token as a JSON array of `QueueSnapshot`s. The JSON is reused for as long as no
+    /// token changes, so polling this is cheap.
+    pub fn get_snapshot_json(&self) -> Result<String, String> {
+        let mut cache = self.1.lock().map_err(|_| "unable to lock the snapshot cache".to_string())?;
+        if let Some((ref snapshots, ref json)) = *cache {
+            let mut same = true;
+            let mut count = 0;
+            self.for_each(|&(ref team, ref channel, ref name), token| {
+                same = same &&
+                       snapshots.get(count).map_or(false, |s| {
+                    s.team_id == *team && s.channel_id == *channel && s.name == *name && s.token == *token
+                });
+                count += 1;
+            });
+            if same && count == snapshots.len() {
+                return Ok(json.clone());
+            }
+        }
+        let snapshots = self.snapshot();
+        let json = serde_json::to_string(&snapshots).map_err(|e| format!("Unable to serialize the queues: {}", e))?;
+        *cache = Some((snapshots, json.clone()));
+        Ok(json)
+    }
+
+    /// Sweep every channel: drop holders who have held their token for `stale_after` seconds or
+    /// more, then delete any queue left empty. Returns the holders who were expired, along with
+    /// which token they were holding, and how many queues were deleted.
+    pub fn purge(&self,
+                 now: Timestamp,
+                 stale_after: Option<u64>)
+                 -> (Vec<((TeamId, ChannelId, TokenName), User)>, usize) {
+        let mut tokens_map = match self.0.lock() {
+            Ok(tokens_map) => tokens_map,
+            Err(_) => return (vec![], 0),
+        };
+        let mut keys: Vec<(TeamId, ChannelId, TokenName)> = tokens_map.keys().cloned().collect();
+        keys.sort();
+        let mut expired = Vec::new();
+        let mut empty = Vec::new();
+        for key in keys {
+            let mut token = match tokens_map[&key].write() {
+                Ok(token) => token,
+                Err(_) => continue,
+            };
+            if let Some(stale_after) = stale_after {
+                if let Some(holder) = token.expire_stale_holder(now, stale_after) {
+                    expired.push((key.clone(), holder));
+                }
+            }
+            if token.len() == 0 {
+                empty.push(key.clone());
+            }
+        }
+        for key in &empty {
+            tokens_map.remove(key);
+        }
+        (expired, empty.len())
+    }
+
+    /// Delete a channel's named token entirely, returning it if it existed
+    pub fn remove(&self, team: &str, channel: &str, name: &str) -> Option<TokenRef> {
+        let mut tokens_map = self.0.lock().unwrap();
+        tokens_map.remove(&(team.to_owned(), channel.to_owned(), name.to_owned()))
     }
 }
 
@@ -61,77 +562,551 @@ impl Token {
     /// ```
     pub fn new() -> Token {
         let users = VecDeque::new();
-        Token { users: users }
+        Token {
+            users: users,
+            holder_since: None,
+            last_reminded: None,
+            response_url: None,
+            max_size: None,
+            frozen: false,
+            description: None,
+            barges: HashMap::new(),
+            drops: HashMap::new(),
+            front_ping: None,
+            hold_times: VecDeque::new(),
+            hold_totals: HashMap::new(),
+            watch_ts: None,
+            auto_drop: HashMap::new(),
+            priority_policy: PriorityPolicy::default(),
+        }
+    }
+
+    /// A queue with `user` already holding the token
+    pub fn new_with_holder(mut user: User) -> Token {
+        let now = clock::now();
+        user.joined_at = Some(now);
+        let mut token = Token::new();
+        token.users.push_back(user);
+        token.holder_since = Some(now);
+        token
+    }
+
+    /// Serialize the queue, eg to move it to another channel with `from_json`
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| format!("Unable to export the queue: {}", e))
+    }
+
+    /// Load a queue exported by `to_json`, rejecting malformed JSON and duplicated users
+    pub fn from_json(json: &str) -> Result<Token, String> {
+        let token: Token = serde_json::from_str(json).map_err(|e| format!("Invalid queue JSON: {}", e))?;
+        for (i, user) in token.users.iter().enumerate() {
+            if token.users.iter().skip(i + 1).any(|u| u == user) {
+                return Err(format!("Invalid queue JSON: {} is in the queue more than once", user));
+            }
+        }
+        Ok(token)
+    }
+
+    pub fn max_size(&self) -> Option<usize> {
+        self.max_size
+    }
+
+    /// Limit how many users may join the queue, or remove the limit with `None`. Anyone already
+    /// queued keeps their place.
+    pub fn set_max_size(&mut self, max_size: Option<usize>) {
+        self.max_size = max_size;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    pub fn priority_policy(&self) -> PriorityPolicy {
+        self.priority_policy
+    }
+
+    pub fn set_priority_policy(&mut self, priority_policy: PriorityPolicy) {
+        self.priority_policy = priority_policy;
+    }
+
+    pub fn watch_ts(&self) -> Option<&str> {
+        self.watch_ts.as_ref().map(|ts| ts.as_str())
+    }
+
+    pub fn set_watch_ts(&mut self, watch_ts: Option<String>) {
+        self.watch_ts = watch_ts;
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_ref().map(|d| d.as_str())
+    }
+
+    pub fn set_description(&mut self, description: Option<String>) {
+        self.description = description;
     }
 
     pub fn len(&self) -> usize {
         self.users.len()
     }
 
-    pub fn get(&mut self, user: User) -> Result<(), &'static str> {
+    /// How many people are waiting behind the holder
+    pub fn len_excluding_holder(&self) -> usize {
+        self.users.len().saturating_sub(1)
+    }
+
+    /// Join the back of the queue, returning the index joined at
+    pub fn get(&mut self, user: User) -> Result<usize, TokenError> {
+        self.get_within(user, None)
+    }
+
+    /// As `get`, but refuse to let more than `max_waiting` people wait behind the holder. Someone
+    /// can always take the token when no one is holding it.
+    pub fn get_within(&mut self, mut user: User, max_waiting: Option<usize>) -> Result<usize, TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
         // We want the queue to be unique
-        if self.users.iter().position(|u| *u == user).is_none() {
-            Ok(self.users.push_back(user))
+        match self.users.iter().position(|u| *u == user) {
+            Some(0) => return Err(TokenError::AlreadyHolding),
+            Some(pos) => return Err(TokenError::AlreadyWaiting(pos)),
+            None => {}
+        }
+        self.check_room(max_waiting)?;
+        let previous = self.clone_order_ids();
+        user.joined_at = Some(clock::now());
+        let priority = user.priority;
+        let index = if priority {
+            self.priority_index()
+        } else {
+            self.users.len()
+        };
+        self.users.insert(index, user);
+        if priority {
+            for passed in self.users.iter_mut().skip(index + 1).filter(|u| !u.priority) {
+                passed.overtaken += 1;
+            }
+        }
+        self.update_holder(&previous);
+        Ok(index)
+    }
+
+    /// Check there's room for one more, given the queue's `max_size` and the `max_waiting` allowed
+    /// behind the holder
+    fn check_room(&self, max_waiting: Option<usize>) -> Result<(), TokenError> {
+        if let Some(max_size) = self.max_size {
+            if self.users.len() >= max_size {
+                return Err(TokenError::QueueFull);
+            }
+        }
+        if let Some(max_waiting) = max_waiting {
+            if self.users.len() > 0 && self.len_excluding_holder() >= max_waiting {
+                return Err(TokenError::WaitlistFull);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where a priority user joins: before the first waiting normal user, as long as that doesn't
+    /// pass anyone who has already been passed as often as the policy allows, otherwise at the
+    /// back
+    fn priority_index(&self) -> usize {
+        let max_overtaken = match self.priority_policy {
+            PriorityPolicy::JumpAhead => usize::max_value(),
+            PriorityPolicy::Interleave(max_overtaken) => max_overtaken,
+        };
+        let start = self.users
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|&(_, u)| !u.priority && u.overtaken >= max_overtaken)
+            .last()
+            .map_or(1, |(index, _)| index + 1);
+        self.users
+            .iter()
+            .enumerate()
+            .skip(start)
+            .find(|&(_, u)| !u.priority)
+            .map_or(self.users.len(), |(index, _)| index)
+    }
+
+    /// How many more seconds the user must wait before rejoining, if they left the queue less than
+    /// `cooldown` seconds before `now`
+    pub fn rejoin_wait(&self, user_id: &str, now: Timestamp, cooldown: Option<u64>) -> Option<u64> {
+        match (cooldown, self.drops.get(user_id)) {
+            (Some(cooldown), Some(&dropped)) if now.saturating_sub(dropped) < cooldown => {
+                Some(cooldown - now.saturating_sub(dropped))
+            }
+            _ => None,
+        }
+    }
+
+    /// Add a user at the given index, or the end of the queue if that is sooner, returning the
+    /// index they were added at. They never go ahead of the holder, and the same limits apply as
+    /// to `get_within`.
+    pub fn insert_at(&mut self, mut user: User, index: usize, max_waiting: Option<usize>) -> Result<usize, TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if self.users.contains(&user) {
+            return Err(TokenError::OtherAlreadyQueued);
+        }
+        self.check_room(max_waiting)?;
+        let index = cmp::min(cmp::max(index, 1), self.users.len());
+        let previous = self.clone_order_ids();
+        user.joined_at = Some(clock::now());
+        self.users.insert(index, user);
+        self.update_holder(&previous);
+        Ok(index)
+    }
+
+    /// Remove someone else from the queue. Unlike `drop` they never rejoin, even if sticky.
+    pub fn kick(&mut self, user_id: &str) -> Result<User, TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        let previous = self.clone_order_ids();
+        match self.remove_by_id(user_id) {
+            Some(kicked) => {
+                self.update_holder(&previous);
+                Ok(kicked)
+            }
+            None => Err(TokenError::OtherNotQueued),
+        }
+    }
+
+    /// Take the user with the given id out of the queue, returning them. Only the first match is
+    /// removed. Callers are responsible for `update_holder`.
+    fn remove_by_id(&mut self, user_id: &str) -> Option<User> {
+        match self.users.iter().position(|u| u.user_id == user_id) {
+            Some(pos) => self.users.remove(pos),
+            None => None,
+        }
+    }
+
+    /// Leave the queue. A sticky holder rejoins the back of the queue, as long as someone else is
+    /// waiting to take over.
+    pub fn drop(&mut self, user: &User) -> Result<(), TokenError> {
+        self.drop_at(user, clock::now())
+    }
+
+    /// As `drop`, remembering that the user left at `now` so `rejoin_wait` can stop them jumping
+    /// straight back in
+    pub fn drop_at(&mut self, user: &User, now: Timestamp) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if let Some(pos) = self.users.iter().position(|u| u == user) {
+            let previous = self.clone_order_ids();
+            let requeue = if pos == 0 && self.users[0].sticky && self.users.len() > 1 {
+                Some(self.users[0].clone())
+            } else {
+                None
+            };
+            self.remove_by_id(&user.user_id);
+            if let Some(mut requeue) = requeue {
+                requeue.joined_at = Some(now);
+                self.users.push_back(requeue);
+            } else {
+                self.drops.insert(user.user_id.clone(), now);
+            }
+            Ok(self.update_holder(&previous))
         } else {
-            Err("You are already in the queue!")
+            Err(TokenError::NotQueued)
         }
     }
 
-    pub fn drop(&mut self, user: &User) -> Result<(), &'static str> {
-        if let Some(_) = self.users.iter().position(|u| u == user) {
-            Ok((&mut self.users).retain(|u| u != user))
+    /// Drop the holder if they have held the token for `max_hold` seconds or more, returning who
+    /// was dropped. Frozen queues are left alone.
+    pub fn expire_stale_holder(&mut self, now: Timestamp, max_hold: u64) -> Option<User> {
+        let holder = match (self.users.front(), self.holder_since) {
+            (Some(holder), Some(since)) if now.saturating_sub(since) >= max_hold => holder.clone(),
+            _ => return None,
+        };
+        self.drop_at(&holder, now).ok().map(|_| holder)
+    }
+
+    /// How long the user asked to hold the token for before being dropped, if they opted in
+    pub fn auto_drop_after(&self, user_id: &str) -> Option<u64> {
+        self.auto_drop.get(user_id).cloned()
+    }
+
+    /// Opt the user in to being dropped after holding the token for `after` seconds, or out with
+    /// `None`
+    pub fn set_auto_drop(&mut self, user_id: &str, after: Option<u64>) {
+        match after {
+            Some(after) => self.auto_drop.insert(user_id.to_owned(), after),
+            None => self.auto_drop.remove(user_id),
+        };
+    }
+
+    /// Drop the holder if they opted in to auto-drop and have held the token for as long as they
+    /// asked, returning who was dropped
+    pub fn expire_auto_drop(&mut self, now: Timestamp) -> Option<User> {
+        let max_hold = match self.users.front().and_then(|holder| self.auto_drop.get(&holder.user_id)) {
+            Some(&max_hold) => max_hold,
+            None => return None,
+        };
+        self.expire_stale_holder(now, max_hold)
+    }
+
+    pub fn step_back(&mut self, user: &User) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if let Some(pos) = self.users.iter().position(|u| u == user) {
+            // Are we at the end of the queue?
+            if pos >= self.len() - 1 {
+                Err(TokenError::AtEnd)
+            } else {
+                let previous = self.clone_order_ids();
+                self.users.swap(pos, pos + 1);
+                Ok(self.update_holder(&previous))
+            }
         } else {
-            Err("You are not in the queue!")
+            Err(TokenError::NotQueued)
         }
     }
 
-    pub fn step_back(&mut self, user: &User) -> Result<(), &'static str> {
+    /// Move back `places` places, or to the end of the queue if that is sooner, returning the new
+    /// index
+    pub fn step_back_by(&mut self, user: &User, places: usize) -> Result<usize, TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
         if let Some(pos) = self.users.iter().position(|u| u == user) {
             // Are we at the end of the queue?
             if pos >= self.len() - 1 {
-                Err("You are at the end of the queue!")
+                Err(TokenError::AtEnd)
             } else {
-                Ok(self.users.swap(pos, pos + 1))
+                let previous = self.clone_order_ids();
+                let new_pos = cmp::min(pos + places, self.len() - 1);
+                // We know there is an item here, so unwrap is safe
+                let moved = self.users.remove(pos).unwrap();
+                self.users.insert(new_pos, moved);
+                self.update_holder(&previous);
+                Ok(new_pos)
             }
         } else {
-            Err("You are not in the queue!")
+            Err(TokenError::NotQueued)
+        }
+    }
+
+    /// Send the holder to the back of the queue, so everyone waiting moves up one place and the
+    /// next person gets the token, but the holder keeps a place
+    pub fn to_back(&mut self, user: &User) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        match self.users.iter().position(|u| u == user) {
+            Some(0) if self.len() == 1 => Err(TokenError::AtEnd),
+            Some(0) => {
+                let previous = self.clone_order_ids();
+                let holder = self.users.pop_front().unwrap();
+                self.users.push_back(holder);
+                Ok(self.update_holder(&previous))
+            }
+            Some(_) => Err(TokenError::NotHolding),
+            None => Err(TokenError::NotQueued),
         }
     }
 
-    pub fn to_front(&mut self, user: &User) -> Result<(), &'static str> {
+    pub fn to_front(&mut self, user: &User) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
         if let Some(pos) = self.users.iter().position(|u| u == user) {
             // Are we already at the front of the queue?
             if pos == 0 {
-                Err("You are already holding the token!")
+                Err(TokenError::AlreadyHolding)
             } else if pos == 1 {
-                Err("You are already at the start of the queue!")
+                // Swapping with ourselves would be a no-op, don't claim we barged
+                Err(TokenError::AlreadyNext)
             } else {
                 Ok(self.users.swap(pos, 1))
             }
         } else {
-            Err("You are not in the queue!")
+            Err(TokenError::NotQueued)
         }
     }
 
-    pub fn steal(&mut self, user: &User) -> Result<User, &'static str> {
-        if let Some(pos) = self.users.iter().position(|u| u == user) {
-            // Are we already at the front of the queue?
-            if pos == 0 {
-                Err("You are already holding the token!")
-            } else {
-                self.users.swap(pos, 0);
+    /// Barge to the front of the waiting users. If `limit` is given as `(count, window)` and the
+    /// user has already barged `count` times in the last `window` seconds, they are sent to the
+    /// back of the queue instead.
+    pub fn barge(&mut self, user: &User, now: Timestamp, limit: Option<(usize, u64)>) -> Result<Barged, TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        // The holder has nowhere to barge to, so shouldn't be sent to the back for trying
+        if self.is_holding(user) {
+            return Err(TokenError::AlreadyHolding);
+        }
+        if let Some((count, window)) = limit {
+            let over_limit = {
+                let recent = self.barges.entry(user.user_id.clone()).or_insert_with(Vec::new);
+                recent.retain(|&at| now.saturating_sub(at) < window);
+                recent.len() >= count
+            };
+            if over_limit {
+                self.barges.remove(&user.user_id);
+                let len = self.len();
+                return match self.step_back_by(user, len) {
+                    Ok(_) | Err(TokenError::AtEnd) => Ok(Barged::ToBack),
+                    Err(e) => Err(e),
+                };
+            }
+        }
+        self.to_front(user)?;
+        if limit.is_some() {
+            self.barges.entry(user.user_id.clone()).or_insert_with(Vec::new).push(now);
+        }
+        Ok(Barged::ToFront)
+    }
+
+    pub fn steal(&mut self, user: &User) -> Result<User, TokenError> {
+        self.steal_at(user, clock::now(), None)
+    }
+
+    /// As `steal`, but refuse to take the token from a holder who got it less than `grace`
+    /// seconds before `now`
+    pub fn steal_at(&mut self, user: &User, now: Timestamp, grace: Option<u64>) -> Result<User, TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if let (Some(grace), Some(since), false) = (grace, self.holder_since, self.is_holding(user)) {
+            let held_for = now.saturating_sub(since);
+            if held_for < grace {
+                return Err(TokenError::InGrace(grace - held_for));
+            }
+        }
+        if self.is_holding(user) {
+            return Err(TokenError::AlreadyHolding);
+        }
+        let previous = self.clone_order_ids();
+        let stealer = match self.remove_by_id(user.id()) {
+            Some(stealer) => stealer,
+            None => return Err(TokenError::NotQueued),
+        };
+        // Any other places they have are no use once they hold the token
+        while self.remove_by_id(user.id()).is_some() {}
+        // They were waiting behind someone, so there is a holder and unwrap is safe
+        let stolen = self.users.pop_front().unwrap();
+        self.users.push_front(stealer);
+        self.update_holder(&previous);
+        Ok(stolen)
+    }
+
+    /// Let the holder pick who gets the token after them, moving that waiter to just behind them
+    pub fn choose_next(&mut self, holder: &User, next_id: &str) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if !self.is_holding(holder) {
+            return Err(TokenError::NotHolding);
+        }
+        match self.users.iter().position(|u| u.user_id == next_id) {
+            Some(0) => Err(TokenError::AlreadyHolding),
+            Some(pos) => {
                 // We know there is an item here, so unwrap is safe
-                Ok(self.users.remove(pos).unwrap())
+                let next = self.users.remove(pos).unwrap();
+                self.users.insert(1, next);
+                Ok(())
             }
-        } else {
-            Err("You are not in the queue!")
+            None => Err(TokenError::OtherNotQueued),
+        }
+    }
+
+    /// Swap whoever is at the two positions, numbered from 0
+    pub fn swap_positions(&mut self, a: usize, b: usize) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if a >= self.users.len() || b >= self.users.len() {
+            return Err(TokenError::NoSuchPosition);
+        }
+        if a == b {
+            return Err(TokenError::SamePosition);
         }
+        let previous = self.clone_order_ids();
+        self.users.swap(a, b);
+        Ok(self.update_holder(&previous))
+    }
+
+    /// Put the queue in the order of the given user ids, which must be exactly the ids of the
+    /// users already in it
+    pub fn reorder(&mut self, new_order: Vec<String>) -> Result<(), TokenError> {
+        if self.frozen {
+            return Err(TokenError::Frozen);
+        }
+        if new_order.len() != self.users.len() {
+            return Err(TokenError::OrderMismatch);
+        }
+        let mut remaining = self.users.clone();
+        let mut users = VecDeque::with_capacity(new_order.len());
+        for id in &new_order {
+            match remaining.iter().position(|u| u.user_id == *id) {
+                Some(pos) => users.push_back(remaining.remove(pos).unwrap()),
+                None => return Err(TokenError::OrderMismatch),
+            }
+        }
+        let previous = self.clone_order_ids();
+        self.users = users;
+        Ok(self.update_holder(&previous))
     }
 
     pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a User> {
         (&self.users).iter()
     }
 
+    /// A copy of the queue, in order, for callers which need it after releasing the token's lock
+    pub fn stable_iter_cloned(&self) -> Vec<User> {
+        self.users.iter().cloned().collect()
+    }
+
+    /// A copy of the token with the given user removed, to preview the effect of them dropping
+    pub fn compacted_clone_without(&self, user_id: &str) -> Token {
+        let mut token = self.clone();
+        token.remove_by_id(user_id);
+        token
+    }
+
+    /// Test if two tokens have the same users in the same order, ignoring when things happened.
+    /// Useful in tests, where `==` compares timestamps too.
+    pub fn same_order(&self, other: &Token) -> bool {
+        self.iter_ids().eq(other.iter_ids())
+    }
+
+    /// A compact single line description of the queue for logs, eg `[alice*, bob]` where `*`
+    /// marks the holder
+    pub fn as_report(&self) -> String {
+        let names: Vec<String> = self.users
+            .iter()
+            .enumerate()
+            .map(|(i, u)| if i == 0 { format!("{}*", u.user_name) } else { u.user_name.clone() })
+            .collect();
+        format!("[{}]", names.join(", "))
+    }
+
+    /// The ids of the queued users, in order. Cheaper than cloning the users to detect changes.
+    pub fn clone_order_ids(&self) -> Vec<String> {
+        self.iter_ids().map(|id| id.to_owned()).collect()
+    }
+
+    /// The ids of the queued users, in order
+    pub fn iter_ids<'a>(&'a self) -> impl Iterator<Item=&'a str> {
+        self.users.iter().map(|u| u.user_id.as_str())
+    }
+
+    /// The queued users in alphabetical order of name, leaving the queue itself alone
+    pub fn sorted_by_name(&self) -> Vec<&User> {
+        let mut users: Vec<&User> = self.users.iter().collect();
+        users.sort_by_key(|u| u.user_name.to_lowercase());
+        users
+    }
+
     pub fn list_user_name(&self) -> Vec<&str> {
         (&self.users).iter().map(|u| u.user_name.as_str()).collect()
     }
@@ -140,29 +1115,595 @@ impl Token {
     pub fn is_holding(&self, user: &User) -> bool {
         self.users.front() == Some(user)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+
+    /// Test if the user with the given id is holding the token
+    pub fn is_holding_id(&self, user_id: &str) -> bool {
+        self.users.front().map_or(false, |u| u.user_id == user_id)
+    }
+
+    /// Update the stored details of the user with the id `old_id`, eg after they changed their
+    /// display name, keeping when they joined and their preferences. Does nothing if they are not
+    /// in the queue.
+    pub fn replace_user(&mut self, old_id: &str, new: User) {
+        if let Some(u) = self.users.iter_mut().find(|u| u.user_id == old_id) {
+            *u = User {
+                joined_at: u.joined_at,
+                sticky: u.sticky,
+                notify_on_front: u.notify_on_front,
+                note: u.note.clone(),
+                priority: u.priority,
+                overtaken: u.overtaken,
+                ..new
+            };
+        }
+    }
+
+    /// Test if the given user is queued under a different name
+    pub fn has_stale_name(&self, user: &User) -> bool {
+        self.users.iter().any(|u| u.user_id == user.user_id && u.user_name != user.user_name)
+    }
+
+    /// The 0-based index of the user in the queue, where 0 is the holder. The queue is always kept
+    /// in the order it is listed in, so this always matches the order shown by `list`.
+    pub fn position(&self, user: &User) -> Option<usize> {
+        self.users.iter().position(|u| u == user)
+    }
+
+    /// The user's place in the queue as people count it, where the holder is #1
+    pub fn number_of(&self, user: &User) -> Option<usize> {
+        self.position(user).map(queue_number)
+    }
+
+    /// Find a user by their id, along with their position in the queue
+    pub fn find_by_id(&self, user_id: &str) -> Option<(usize, &User)> {
+        self.users.iter().enumerate().find(|&(_, u)| u.user_id == user_id)
+    }
+
+    pub fn front(&self) -> Option<&User> {
+        self.users.front()
+    }
+
+    pub fn holder_since(&self) -> Option<Timestamp> {
+        self.holder_since
+    }
+
+    pub fn last_reminded(&self) -> Option<Timestamp> {
+        self.last_reminded
+    }
+
+    pub fn set_last_reminded(&mut self, at: Timestamp) {
+        self.last_reminded = Some(at);
+    }
+
+    pub fn response_url(&self) -> Option<&str> {
+        self.response_url.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn set_response_url(&mut self, url: String) {
+        self.response_url = Some(url);
+    }
+
+    /// The average time recent holders have held the token for, if anyone has held it yet
+    pub fn average_hold(&self) -> Option<u64> {
+        if self.hold_times.is_empty() {
+            None
+        } else {
+            Some(self.hold_times.iter().sum::<u64>() / self.hold_times.len() as u64)
+        }
+    }
+
+    /// The ids of the `limit` users who have held the token longest in total, with how many
+    /// seconds they held it for, longest first. Only finished holds are counted.
+    pub fn hold_leaderboard(&self, limit: usize) -> Vec<(&str, u64)> {
+        let mut totals: Vec<(&str, u64)> = self.hold_totals.iter().map(|(id, &total)| (id.as_str(), total)).collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        totals.truncate(limit);
+        totals
+    }
+
+    /// Estimate how many seconds the user at `position` will wait for the token, assuming everyone
+    /// ahead holds it for the recent average. `None` without any hold times to go on.
+    pub fn estimate_wait(&self, position: usize, now: Timestamp) -> Option<u64> {
+        let average = match self.average_hold() {
+            Some(average) => average,
+            None => return None,
+        };
+        if position == 0 {
+            return Some(0);
+        }
+        let held_for = self.holder_since.map(|since| now.saturating_sub(since)).unwrap_or(0);
+        Some(average.saturating_sub(held_for) + average * (position as u64 - 1))
+    }
+
+    /// Restart the holding clock if the holder has changed since the queue was `previous`
+    fn update_holder(&mut self, previous: &[String]) {
+        if self.front_moved(previous) {
+            let now = clock::now();
+            if let Some(since) = self.holder_since {
+                let held_for = now.saturating_sub(since);
+                if self.hold_times.len() >= HOLD_TIMES {
+                    self.hold_times.pop_front();
+                }
+                self.hold_times.push_back(held_for);
+                if let Some(holder_id) = previous.first() {
+                    *self.hold_totals.entry(holder_id.clone()).or_insert(0) += held_for;
+                }
+            }
+            self.holder_since = self.users.front().map(|_| now);
+            self.last_reminded = None;
+            let ping = self.front_changed_since(previous)
+                .and_then(|u| if u.notify_on_front { Some(u.clone()) } else { None });
+            self.front_ping = ping;
+        }
+    }
+
+    /// Whether someone else is at the front than was in `previous`, including no one
+    fn front_moved(&self, previous: &[String]) -> bool {
+        self.users.front().map(|u| u.id()) != previous.first().map(|id| id.as_str())
+    }
+
+    /// The new holder, if the front of the queue is not who it was when the queue's ids were
+    /// `previous`
+    pub fn front_changed_since(&self, previous: &[String]) -> Option<&User> {
+        if self.front_moved(previous) {
+            self.users.front()
+        } else {
+            None
+        }
+    }
+
+    /// The holder to mention now that they've reached the front, if they asked to be. Each holder
+    /// is only returned once.
+    pub fn take_front_ping(&mut self) -> Option<User> {
+        self.front_ping.take()
+    }
+}
+
+impl<'a> IntoIterator for &'a Token {
+    type Item = &'a User;
+    type IntoIter = vec_deque::Iter<'a, User>;
+
+    fn into_iter(self) -> vec_deque::Iter<'a, User> {
+        self.users.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use test_utils;
+
+    #[test]
+    fn test_get() {
+        let mut t = Token::new();
+        let u = User::new("id".to_string(), "name".to_string());
+        t.get(u.clone()).unwrap();
+
+        assert!(t.is_holding(&u));
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut t = Token::new();
+        for i in 0..5 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+
+        let new = User::new("new".to_string(), "new".to_string());
+        assert_eq!(t.insert_at(new.clone(), 2, None), Ok(2));
+        assert_eq!(t.list_user_name(), vec!["name0", "name1", "new", "name2", "name3", "name4"]);
+        assert!(t.insert_at(new.clone(), 0, None).is_err());
+
+        let late = User::new("late".to_string(), "late".to_string());
+        assert_eq!(t.insert_at(late, 100, None), Ok(6));
+
+        // Nobody is put ahead of the holder
+        let early = User::new("early".to_string(), "early".to_string());
+        assert_eq!(t.insert_at(early, 0, None), Ok(1));
+        assert_eq!(t.front().unwrap().id(), "id0");
+
+        let mut empty = Token::new();
+        assert_eq!(empty.insert_at(new.clone(), 3, None), Ok(0));
+        assert!(empty.is_holding(&new));
+    }
+
+    #[test]
+    fn test_insert_at_limits() {
+        let mut t = Token::new();
+        for i in 0..3 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        let new = User::new("new".to_string(), "new".to_string());
+        assert_eq!(t.insert_at(new.clone(), 1, Some(2)), Err(TokenError::WaitlistFull));
+        t.set_max_size(Some(3));
+        assert_eq!(t.insert_at(new.clone(), 1, None), Err(TokenError::QueueFull));
+        assert_eq!(t.len(), 3);
+    }
+
+    #[test]
+    fn test_get_position() {
+        let mut t = Token::new();
+        for i in 0..4 {
+            assert_eq!(t.get(User::new(format!("id{}", i), format!("name{}", i))), Ok(i));
+        }
+        assert_eq!(t.get(User::new("id2".to_string(), "name2".to_string())),
+                   Err(TokenError::AlreadyWaiting(2)));
+    }
+
+    #[test]
+    fn test_get_priority() {
+        let mut t = test_utils::token_with_users(3);
+        let mut p0 = test_utils::user(10);
+        p0.set_priority(true);
+        let mut p1 = test_utils::user(11);
+        p1.set_priority(true);
+
+        // Priority users go ahead of the waiting normal users, but not the holder or each other
+        assert_eq!(t.get(p0), Ok(1));
+        assert_eq!(t.get(p1), Ok(2));
+        assert_eq!(t.get(test_utils::user(3)), Ok(5));
+        assert_eq!(t.list_user_name(), vec!["name0", "name10", "name11", "name1", "name2", "name3"]);
+
+        // With no one holding, a priority user just takes the token
+        let mut empty = Token::new();
+        let mut p = test_utils::user(0);
+        p.set_priority(true);
+        assert_eq!(empty.get(p), Ok(0));
+    }
+
+    #[test]
+    fn test_get_priority_interleave() {
+        let priority = |i: usize| {
+            let mut u = test_utils::user(i);
+            u.set_priority(true);
+            u
+        };
+        let mut t = test_utils::token_with_users(3);
+        t.set_priority_policy(PriorityPolicy::Interleave(2));
+        for i in 10..14 {
+            t.get(priority(i)).unwrap();
+        }
+        // Both normal users have been passed twice, so later priority users wait behind them
+        assert_eq!(t.list_user_name(),
+                   vec!["name0", "name10", "name11", "name1", "name2", "name12", "name13"]);
+    }
+
+    #[test]
+    fn test_priority_starvation() {
+        // Each time the holder finishes another priority user turns up. Jumping ahead, the normal
+        // user never gets the token, but interleaved they reach the front after `run` turns.
+        let turns_until_normal_holds = |policy: PriorityPolicy| {
+            let mut t = test_utils::token_with_users(2);
+            t.set_priority_policy(policy);
+            for turn in 0..10 {
+                if t.is_holding_id("id1") {
+                    return Some(turn);
+                }
+                let mut u = test_utils::user(10 + turn);
+                u.set_priority(true);
+                t.get(u).unwrap();
+                let holder = t.front().unwrap().clone();
+                t.drop(&holder).unwrap();
+            }
+            None
+        };
+        assert_eq!(turns_until_normal_holds(PriorityPolicy::JumpAhead), None);
+        assert_eq!(turns_until_normal_holds(PriorityPolicy::Interleave(2)), Some(3));
+    }
+
+    #[test]
+    fn test_get_again() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+
+        assert_eq!(t.get(u0.clone()), Err(TokenError::AlreadyHolding));
+        assert_eq!(t.get(u1.clone()), Err(TokenError::AlreadyWaiting(1)));
+        assert_eq!(TokenError::AlreadyWaiting(1).to_string(), "You are already waiting at #2!");
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn test_get_concurrent() {
+        use std::thread;
+
+        let token: TokenRef = Arc::new(RwLock::new(Token::new()));
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let token = token.clone();
+                thread::spawn(move || {
+                    let user = User::new(format!("id{}", i), format!("name{}", i));
+                    let index = token.write().unwrap().get(user).unwrap();
+                    index
+                })
+            })
+            .collect();
+        let mut indexes: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        indexes.sort();
+
+        // Whoever lost the race is told they're second
+        assert_eq!(indexes, vec![0, 1]);
+        assert_eq!(token.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut t = Token::new();
+        t.set_max_size(Some(2));
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        assert_eq!(t.get(User::new("id2".to_string(), "name2".to_string())),
+                   Err(TokenError::QueueFull));
+
+        t.set_max_size(None);
+        assert_eq!(t.get(User::new("id2".to_string(), "name2".to_string())), Ok(2));
+    }
+
+    #[test]
+    fn test_new_with_holder() {
+        let u = User::new("id".to_string(), "name".to_string());
+        let t = Token::new_with_holder(u.clone());
+        assert!(t.is_holding(&u));
+        assert_eq!(t.len(), 1);
+        assert!(t.holder_since().is_some());
+        assert!(t.front().unwrap().joined_at().is_some());
+    }
+
+    #[test]
+    fn test_drop() {
+        let u = User::new("id".to_string(), "name".to_string());
+        let mut t = Token::new_with_holder(u.clone());
+        assert!(t.is_holding(&u));
+        t.drop(&u).unwrap();
+        assert!(!t.is_holding(&u));
+    }
+
+    #[test]
+    fn test_user_try_new() {
+        assert_eq!(User::try_new("".to_string(), "name0".to_string()), Err(TokenError::MissingId));
+        assert_eq!(User::try_new("  ".to_string(), "name0".to_string()), Err(TokenError::MissingId));
+        let user = User::try_new("id0".to_string(), "name0".to_string()).unwrap();
+        assert_eq!((user.id(), user.name()), ("id0", "name0"));
+    }
+
+    #[test]
+    fn test_user_formatting() {
+        let u = User::new("id0".to_string(), "name0".to_string());
+        assert_eq!(u.as_slack_str(), "<@id0|name0>");
+        assert_eq!(u.mention_only(), "<@id0>");
+        assert_eq!(u.name_only(), "name0");
+    }
+
+    #[test]
+    fn test_drop_sticky() {
+        let mut t = Token::new();
+        let mut u0 = User::new("id0".to_string(), "name0".to_string());
+        u0.set_sticky(true);
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+
+        t.drop(&u0).unwrap();
+        assert_eq!(t.list_user_name(), vec!["name1", "name0"]);
+        assert!(t.is_holding(&u1));
+
+        // Only the holder goes round again
+        t.drop(&u0).unwrap();
+        assert_eq!(t.list_user_name(), vec!["name1"]);
+    }
+
+    #[test]
+    fn test_kick() {
+        let mut t = Token::new();
+        let mut u0 = User::new("id0".to_string(), "name0".to_string());
+        u0.set_sticky(true);
+        t.get(u0.clone()).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+
+        assert_eq!(t.kick("id0").map(|u| u.name().to_owned()), Ok("name0".to_string()));
+        assert_eq!(t.list_user_name(), vec!["name1"]);
+        assert_eq!(t.kick("id0"), Err(TokenError::OtherNotQueued));
+    }
+
+    #[test]
+    fn test_drop_not_sticky() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+
+        t.drop(&u0).unwrap();
+        assert_eq!(t.list_user_name(), vec!["name1"]);
+    }
+
+    #[test]
+    fn test_frozen() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.set_frozen(true);
+
+        assert_eq!(t.get(u2.clone()), Err(TokenError::Frozen));
+        assert_eq!(t.insert_at(u2.clone(), 0, None), Err(TokenError::Frozen));
+        assert_eq!(t.drop(&u0), Err(TokenError::Frozen));
+        assert_eq!(t.step_back(&u0), Err(TokenError::Frozen));
+        assert_eq!(t.barge(&u1, 0, None), Err(TokenError::Frozen));
+        assert_eq!(t.steal(&u1), Err(TokenError::Frozen));
+        assert_eq!(t.list_user_name(), vec!["name0", "name1"]);
+        assert!(t.is_holding(&u0));
+
+        t.set_frozen(false);
+        assert_eq!(t.get(u2.clone()), Ok(2));
+        assert_eq!(t.step_back(&u0), Ok(()));
+        assert_eq!(t.list_user_name(), vec!["name1", "name0", "name2"]);
+    }
+
+    #[test]
+    fn test_sorted_by_name() {
+        let mut t = Token::new();
+        for name in &["carol", "alice", "Bob"] {
+            t.get(User::new(format!("id-{}", name), name.to_string())).unwrap();
+        }
+
+        let sorted: Vec<&str> = t.sorted_by_name().iter().map(|u| u.name()).collect();
+        assert_eq!(sorted, vec!["alice", "Bob", "carol"]);
+        assert!(sorted != t.list_user_name());
+        // The queue keeps its order
+        assert_eq!(t.list_user_name(), vec!["carol", "alice", "Bob"]);
+    }
+
+    #[test]
+    fn test_choose_next() {
+        let mut t = Token::new();
+        for i in 0..4 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        let holder = User::new("id0".to_string(), "name0".to_string());
+        let waiter = User::new("id1".to_string(), "name1".to_string());
+
+        assert_eq!(t.choose_next(&holder, "id2"), Ok(()));
+        assert_eq!(t.list_user_name(), vec!["name0", "name2", "name1", "name3"]);
+        assert!(t.is_holding(&holder));
+
+        assert_eq!(t.choose_next(&waiter, "id3"), Err(TokenError::NotHolding));
+        assert_eq!(t.choose_next(&holder, "id0"), Err(TokenError::AlreadyHolding));
+        assert_eq!(t.choose_next(&holder, "id9"), Err(TokenError::OtherNotQueued));
+        assert_eq!(t.list_user_name(), vec!["name0", "name2", "name1", "name3"]);
+    }
+
+    #[test]
+    fn test_swap_positions() {
+        let mut t = test_utils::token_with_users(3);
+
+        assert_eq!(t.swap_positions(0, 2), Ok(()));
+        assert_eq!(t.list_user_name(), vec!["name2", "name1", "name0"]);
+        assert_eq!(t.swap_positions(1, 1), Err(TokenError::SamePosition));
+        assert_eq!(t.swap_positions(1, 3), Err(TokenError::NoSuchPosition));
+        assert_eq!(t.list_user_name(), vec!["name2", "name1", "name0"]);
+    }
+
+    #[test]
+    fn test_front_ping() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let mut u1 = User::new("id1".to_string(), "name1".to_string());
+        u1.set_notify_on_front(true);
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+        assert_eq!(t.take_front_ping(), None);
+
+        t.drop(&u0).unwrap();
+        assert_eq!(t.take_front_ping().map(|u| u.id().to_owned()), Some("id1".to_string()));
+        assert_eq!(t.take_front_ping(), None);
+
+        // Reaching the front without opting in doesn't ping
+        t.drop(&u1).unwrap();
+        assert_eq!(t.take_front_ping(), None);
+    }
+
+    #[test]
+    fn test_estimate_wait() {
+        let mut t = Token::new();
+        for i in 0..3 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        assert_eq!(t.average_hold(), None);
+        assert_eq!(t.estimate_wait(2, clock::now()), None);
+
+        t.hold_times = vec![10 * 60, 20 * 60].into_iter().collect();
+        let since = t.holder_since().unwrap();
+        assert_eq!(t.average_hold(), Some(15 * 60));
+        assert_eq!(t.estimate_wait(0, since), Some(0));
+        // The holder has 10m left on average, then #2 takes another 15m
+        assert_eq!(t.estimate_wait(1, since + 5 * 60), Some(10 * 60));
+        assert_eq!(t.estimate_wait(2, since + 5 * 60), Some(25 * 60));
+        // Holders running over don't make the wait negative
+        assert_eq!(t.estimate_wait(1, since + 60 * 60), Some(0));
+    }
+
+    #[test]
+    fn test_get_within() {
+        let mut t = Token::new();
+        // The holder always fits, even with no room to wait
+        assert_eq!(t.get_within(User::new("id0".to_string(), "name0".to_string()), Some(0)), Ok(0));
+        assert_eq!(t.get_within(User::new("id1".to_string(), "name1".to_string()), Some(0)),
+                   Err(TokenError::WaitlistFull));
+
+        for i in 1..3 {
+            assert_eq!(t.get_within(User::new(format!("id{}", i), format!("name{}", i)), Some(2)), Ok(i));
+        }
+        assert_eq!(t.len_excluding_holder(), 2);
+        assert_eq!(t.get_within(User::new("id3".to_string(), "name3".to_string()), Some(2)),
+                   Err(TokenError::WaitlistFull));
+        assert_eq!(t.len(), 3);
+    }
+
+    #[test]
+    fn test_steal_grace() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        let since = t.holder_since().unwrap();
+
+        assert_eq!(t.steal_at(&u1, since + 10, Some(60)), Err(TokenError::InGrace(50)));
+        assert!(t.is_holding(&u0));
+        assert_eq!(t.steal_at(&u1, since + 60, Some(60)).map(|u| u.id().to_owned()),
+                   Ok("id0".to_string()));
+        assert!(t.is_holding(&u1));
+    }
+
+    #[test]
+    fn test_number_of() {
+        let mut t = Token::new();
+        for i in 0..4 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        assert_eq!(t.number_of(&User::new("id0".to_string(), "name0".to_string())), Some(1));
+        assert_eq!(t.number_of(&User::new("id3".to_string(), "name3".to_string())), Some(4));
+        assert_eq!(t.number_of(&User::new("id4".to_string(), "name4".to_string())), None);
+        assert_eq!(queue_index(4), Some(3));
+        assert_eq!(queue_index(0), None);
+    }
 
     #[test]
-    fn test_get() {
+    fn test_stable_iter_cloned() {
         let mut t = Token::new();
-        let u = User::new("id".to_string(), "name".to_string());
-        t.get(u.clone()).unwrap();
+        for i in 0..3 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        let snapshot = t.stable_iter_cloned();
+        assert_eq!(snapshot, t.iter().cloned().collect::<Vec<User>>());
 
-        assert!(t.is_holding(&u));
+        t.drop(&snapshot[0]).unwrap();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot[0].name(), "name0");
+        assert_eq!(t.len(), 2);
     }
 
     #[test]
-    fn test_drop() {
+    fn test_rejoin_wait() {
         let mut t = Token::new();
-        let u = User::new("id".to_string(), "name".to_string());
-        t.get(u.clone()).unwrap();
-        assert!(t.is_holding(&u));
-        t.drop(&u).unwrap();
-        assert!(!t.is_holding(&u));
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        t.get(u0.clone()).unwrap();
+        assert_eq!(t.rejoin_wait("id0", 1000, Some(300)), None);
+
+        t.drop_at(&u0, 1000).unwrap();
+        assert_eq!(t.rejoin_wait("id0", 1000, Some(300)), Some(300));
+        assert_eq!(t.rejoin_wait("id0", 1200, Some(300)), Some(100));
+        assert_eq!(t.rejoin_wait("id0", 1300, Some(300)), None);
+        assert_eq!(t.rejoin_wait("id0", 1000, None), None);
+        assert_eq!(t.rejoin_wait("id1", 1000, Some(300)), None);
     }
 
     #[test]
@@ -199,6 +1740,88 @@ mod tests {
         assert_eq!(t.list_user_name(), vec!["name0", "name1", "name2", "name3"]);
     }
 
+    #[test]
+    fn test_clone_order_ids() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+        assert_eq!(t.clone_order_ids(), vec!["id0", "id1", "id2"]);
+
+        t.to_front(&u2).unwrap();
+        assert_eq!(t.clone_order_ids(), vec!["id0", "id2", "id1"]);
+    }
+
+    #[test]
+    fn test_compacted_clone_without() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+
+        let preview = t.compacted_clone_without("id0");
+        assert_eq!(preview.front(), Some(&u1));
+        assert_eq!(preview.len(), 1);
+        // The original is untouched
+        assert!(t.is_holding(&u0));
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn test_same_order() {
+        let t0 = Token::from_json("{\"users\": [{\"user_id\": \"id0\", \"user_name\": \"name0\", \"joined_at\": 10}, \
+                                   {\"user_id\": \"id1\", \"user_name\": \"name1\", \"joined_at\": 20}], \
+                                   \"holder_since\": 10}")
+            .unwrap();
+        let t1 = Token::from_json("{\"users\": [{\"user_id\": \"id0\", \"user_name\": \"name0\", \"joined_at\": 50}, \
+                                   {\"user_id\": \"id1\", \"user_name\": \"name1\", \"joined_at\": 60}], \
+                                   \"holder_since\": 50}")
+            .unwrap();
+        assert!(t0 != t1);
+        assert!(t0.same_order(&t1));
+
+        let mut t2 = t1.clone();
+        t2.step_back(&User::new("id0".to_string(), "name0".to_string())).unwrap();
+        assert!(!t0.same_order(&t2));
+    }
+
+    #[test]
+    fn test_as_report() {
+        let mut t = Token::new();
+        assert_eq!(t.as_report(), "[]");
+        t.get(User::new("id0".to_string(), "alice".to_string())).unwrap();
+        assert_eq!(t.as_report(), "[alice*]");
+        t.get(User::new("id1".to_string(), "bob".to_string())).unwrap();
+        t.get(User::new("id2".to_string(), "carol".to_string())).unwrap();
+        assert_eq!(t.as_report(), "[alice*, bob, carol]");
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let mut t = Token::new();
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+
+        let mut names = vec![];
+        for u in &t {
+            names.push(u.name());
+        }
+        assert_eq!(names, vec!["name0", "name1"]);
+    }
+
+    #[test]
+    fn test_iter_ids() {
+        let mut t = Token::new();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id2".to_string(), "name2".to_string())).unwrap();
+        assert_eq!(t.iter_ids().collect::<Vec<_>>(), vec!["id1", "id0", "id2"]);
+    }
+
     #[test]
     fn test_step_back() {
         let mut t = Token::new();
@@ -221,6 +1844,48 @@ mod tests {
         assert!(t.step_back(&u0).is_err());
     }
 
+    #[test]
+    fn test_to_back() {
+        let mut t = test_utils::token_with_users(4);
+        let users = test_utils::users(4);
+
+        assert_eq!(t.to_back(&users[0]), Ok(()));
+        assert_eq!(t.list_user_name(), vec!["name1", "name2", "name3", "name0"]);
+        assert!(t.is_holding(&users[1]));
+
+        // Only the holder can requeue
+        assert_eq!(t.to_back(&users[2]), Err(TokenError::NotHolding));
+        assert_eq!(t.to_back(&test_utils::user(4)), Err(TokenError::NotQueued));
+
+        let mut alone = test_utils::token_with_users(1);
+        assert_eq!(alone.to_back(&users[0]), Err(TokenError::AtEnd));
+        assert!(alone.is_holding(&users[0]));
+    }
+
+    #[test]
+    fn test_step_back_by() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        let u3 = User::new("id3".to_string(), "name3".to_string());
+        let u4 = User::new("id4".to_string(), "name4".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+        t.get(u3.clone()).unwrap();
+        t.get(u4.clone()).unwrap();
+
+        assert_eq!(t.step_back_by(&u0, 2), Ok(2));
+        assert_eq!(t.list_user_name(), vec!["name1", "name2", "name0", "name3", "name4"]);
+        assert!(t.is_holding(&u1));
+
+        // Clamped to the end of the queue
+        assert_eq!(t.step_back_by(&u1, 10), Ok(4));
+        assert_eq!(t.list_user_name(), vec!["name2", "name0", "name3", "name4", "name1"]);
+        assert_eq!(t.step_back_by(&u1, 1), Err(TokenError::AtEnd));
+    }
+
     #[test]
     fn test_to_front() {
         let mut t = Token::new();
@@ -239,6 +1904,81 @@ mod tests {
         assert!(t.to_front(&u2).is_err());
     }
 
+    #[test]
+    fn test_to_front_already_next() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+
+        assert_eq!(t.to_front(&u1), Err(TokenError::AlreadyNext));
+        assert_eq!(t.list_user_name(), vec!["name0", "name1"]);
+    }
+
+    #[test]
+    fn test_position() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        let u3 = User::new("id3".to_string(), "name3".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+        assert_eq!(t.position(&u2), Some(2));
+        assert_eq!(t.position(&u3), None);
+
+        // Someone who jumped the queue is reported where they are listed
+        t.to_front(&u2).unwrap();
+        assert_eq!(t.position(&u2), Some(1));
+        assert_eq!(t.position(&u1), Some(2));
+        assert_eq!(t.list_user_name(), vec!["name0", "name2", "name1"]);
+    }
+
+    #[test]
+    fn test_barge_limit() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        let u3 = User::new("id3".to_string(), "name3".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+        t.get(u3.clone()).unwrap();
+
+        let limit = Some((2, 600));
+        assert_eq!(t.barge(&u3, 1000, limit), Ok(Barged::ToFront));
+        t.step_back_by(&u3, 2).unwrap();
+        assert_eq!(t.barge(&u3, 1100, limit), Ok(Barged::ToFront));
+        assert_eq!(t.list_user_name(), vec!["name0", "name3", "name1", "name2"]);
+
+        // A third barge within the window sends them to the back
+        assert_eq!(t.barge(&u3, 1200, limit), Ok(Barged::ToBack));
+        assert_eq!(t.list_user_name(), vec!["name0", "name1", "name2", "name3"]);
+
+        // Once the window has passed they can barge again
+        assert_eq!(t.barge(&u3, 2000, limit), Ok(Barged::ToFront));
+        assert_eq!(t.list_user_name(), vec!["name0", "name3", "name1", "name2"]);
+    }
+
+    #[test]
+    fn test_barge_unlimited() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        t.get(u2.clone()).unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(t.barge(&u2, 1000, None), Ok(Barged::ToFront));
+            t.step_back(&u2).unwrap();
+        }
+    }
+
     #[test]
     fn test_steal() {
         let mut t = Token::new();
@@ -257,20 +1997,408 @@ mod tests {
         assert_eq!(t.list_user_name(), vec!["name3", "name1"]);
 
         assert!(t.steal(&u2).is_err());
+
+        // Someone queued twice only holds the token once they steal it
+        t.users.push_back(u2.clone());
+        t.users.push_back(u2.clone());
+        assert_eq!(t.steal(&u2).map(|u| u.id().to_owned()), Ok("id3".to_string()));
+        assert_eq!(t.list_user_name(), vec!["name2", "name1"]);
     }
 
     #[test]
-    fn test_is_holding() {
+    fn test_replace_user() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let mut u1 = User::new("id1".to_string(), "name1".to_string());
+        u1.set_sticky(true);
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        let joined = t.find_by_id("id1").unwrap().1.joined_at();
+
+        let renamed = User::new("id1".to_string(), "renamed".to_string());
+        assert!(t.has_stale_name(&renamed));
+        t.replace_user("id1", renamed.clone());
+        assert!(!t.has_stale_name(&renamed));
+        assert_eq!(t.list_user_name(), vec!["name0", "renamed"]);
+        // Only who they are changes, not their place in the queue
+        let (_, replaced) = t.find_by_id("id1").unwrap();
+        assert_eq!(replaced.joined_at(), joined);
+        assert!(replaced.is_sticky());
+
+        let rendered = serde_json::to_string(&::slack::format_list(None, t.iter())).unwrap();
+        assert!(rendered.contains("<@id1|renamed>"));
+        assert!(!rendered.contains("name1"));
+
+        // Users not in the queue aren't added
+        t.replace_user("id2", User::new("id2".to_string(), "name2".to_string()));
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut t = Token::new();
+        t.get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+
+        let json = t.to_json().unwrap();
+        assert_eq!(Token::from_json(&json).unwrap(), t);
+
+        let empty = Token::new();
+        assert_eq!(Token::from_json(&empty.to_json().unwrap()).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_description() {
+        let mut t = Token::new();
+        assert_eq!(t.description(), None);
+        t.set_description(Some("prod deploy lock".to_string()));
+        assert_eq!(t.description(), Some("prod deploy lock"));
+        assert_eq!(Token::from_json(&t.to_json().unwrap()).unwrap().description(),
+                   Some("prod deploy lock"));
+        // Tokens saved before descriptions existed have none
+        assert_eq!(Token::from_json("{\"users\": []}").unwrap().description(), None);
+    }
+
+    #[test]
+    fn test_from_json_invalid() {
+        assert!(Token::from_json("").is_err());
+        assert!(Token::from_json("{\"users\": [").is_err());
+        assert!(Token::from_json("{\"users\": [{\"user_id\": \"id0\"}]}").is_err());
+
+        let duplicated = "{\"users\": [{\"user_id\": \"id0\", \"user_name\": \"name0\"}, \
+                          {\"user_id\": \"id0\", \"user_name\": \"name0\"}]}";
+        assert!(Token::from_json(duplicated).is_err());
+    }
+
+    #[test]
+    fn test_tokens_remove() {
+        let tokens = Tokens::new();
+        tokens.get_or_create("team", "channel", DEFAULT_TOKEN);
+        tokens.get_or_create("team", "channel", "demo");
+        tokens.get_or_create("team", "other", "demo");
+
+        let names = |tokens: &Tokens| -> Vec<TokenName> {
+            tokens.for_channel("team", "channel").into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(names(&tokens), vec!["default", "demo"]);
+
+        assert!(tokens.remove("team", "channel", "demo").is_some());
+        assert_eq!(names(&tokens), vec!["default"]);
+        assert!(tokens.remove("team", "channel", "demo").is_none());
+        // Other channels' tokens with the same name are untouched
+        assert_eq!(tokens.for_channel("team", "other").len(), 1);
+    }
+
+    #[test]
+    fn test_tokens_for_each() {
+        let tokens = Tokens::new();
+        let demo = tokens.get_or_create("team", "channel", "demo");
+        demo.write().unwrap().get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        demo.write().unwrap().get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        let other = tokens.get_or_create("team", "other", DEFAULT_TOKEN);
+        other.write().unwrap().get(User::new("id2".to_string(), "name2".to_string())).unwrap();
+        tokens.get_or_create("team", "channel", DEFAULT_TOKEN);
+
+        let mut visited = Vec::new();
+        let mut total = 0;
+        tokens.for_each(|&(_, ref channel, ref name), token| {
+            visited.push(format!("{}/{}", channel, name));
+            total += token.len();
+        });
+        assert_eq!(visited, vec!["channel/default", "channel/demo", "other/default"]);
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn test_front_changed_since() {
         let mut t = Token::new();
         let u0 = User::new("id0".to_string(), "name0".to_string());
         let u1 = User::new("id1".to_string(), "name1".to_string());
-        let u2 = User::new("id2".to_string(), "name2".to_string());
-        let u3 = User::new("id3".to_string(), "name3".to_string());
+
+        // Empty to holding
+        let previous = t.clone_order_ids();
         t.get(u0.clone()).unwrap();
+        assert_eq!(t.front_changed_since(&previous), Some(&u0));
+
+        // Joining behind the holder leaves the front alone
+        let previous = t.clone_order_ids();
         t.get(u1.clone()).unwrap();
-        t.get(u2.clone()).unwrap();
-        t.get(u3.clone()).unwrap();
+        assert_eq!(t.front_changed_since(&previous), None);
+
+        let previous = t.clone_order_ids();
+        t.drop(&u0).unwrap();
+        assert_eq!(t.front_changed_since(&previous), Some(&u1));
+
+        // Holding to empty has no new holder
+        let previous = t.clone_order_ids();
+        t.drop(&u1).unwrap();
+        assert_eq!(t.front_changed_since(&previous), None);
+        assert_eq!(t.front_changed_since(&[]), None);
+    }
+
+    #[test]
+    fn test_remove_by_id() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        t.get(u0.clone()).unwrap();
+        t.get(u1.clone()).unwrap();
+        assert_eq!(t.remove_by_id("id2"), None);
+        assert_eq!(t.remove_by_id("id1"), Some(u1.clone()));
+        assert_eq!(t.len(), 1);
+
+        // Even if someone somehow ended up in the queue twice, only one entry goes at a time
+        t.users.push_back(u0.clone());
+        t.drop(&u0).unwrap();
+        assert_eq!(t.list_user_name(), vec!["name0"]);
+        t.users.push_back(u0.clone());
+        t.kick("id0").unwrap();
+        assert_eq!(t.len(), 1);
+        assert_eq!(t.compacted_clone_without("id0").len(), 0);
+    }
+
+    #[test]
+    fn test_hold_leaderboard() {
+        let mut t = Token::new();
+        let u0 = User::new("id0".to_string(), "name0".to_string());
+        let u1 = User::new("id1".to_string(), "name1".to_string());
+        let u2 = User::new("id2".to_string(), "name2".to_string());
+        assert!(t.hold_leaderboard(3).is_empty());
+
+        // Hand the token round, backdating each hold to say how long it lasted
+        let hold = |t: &mut Token, user: &User, secs: u64| {
+            t.get(user.clone()).unwrap();
+            t.holder_since = Some(clock::now() - secs);
+            t.drop(user).unwrap();
+        };
+        hold(&mut t, &u0, 600);
+        hold(&mut t, &u1, 1200);
+        hold(&mut t, &u2, 60);
+        hold(&mut t, &u0, 900);
+
+        let ids = |board: Vec<(&str, u64)>| -> Vec<String> { board.into_iter().map(|(id, _)| id.to_owned()).collect() };
+        assert_eq!(ids(t.hold_leaderboard(3)), vec!["id0", "id1", "id2"]);
+        assert_eq!(ids(t.hold_leaderboard(2)), vec!["id0", "id1"]);
+        // Allow for the clock ticking over while the test runs
+        let (_, total) = t.hold_leaderboard(1)[0];
+        assert!(total >= 1500 && total <= 1502);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut t = Token::new();
+        for i in 0..3 {
+            t.get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        let ids = |t: &Token| -> Vec<String> { t.iter().map(|u| u.id().to_owned()).collect() };
+        let order = |ids: &[&str]| -> Vec<String> { ids.iter().map(|id| id.to_string()).collect() };
+
+        t.reorder(order(&["id2", "id0", "id1"])).unwrap();
+        assert_eq!(ids(&t), order(&["id2", "id0", "id1"]));
+        assert!(t.is_holding_id("id2"));
+
+        let before = t.clone();
+        assert_eq!(t.reorder(order(&["id2", "id0"])), Err(TokenError::OrderMismatch));
+        assert_eq!(t.reorder(order(&["id2", "id0", "id3"])), Err(TokenError::OrderMismatch));
+        assert_eq!(t.reorder(order(&["id2", "id0", "id0"])), Err(TokenError::OrderMismatch));
+        assert_eq!(t, before);
+    }
+
+    #[test]
+    fn test_expire_stale_holder() {
+        let mut t = Token::new_with_holder(User::new("id0".to_string(), "name0".to_string()));
+        t.get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        let since = t.holder_since().unwrap();
+
+        assert_eq!(t.expire_stale_holder(since + 59, 60), None);
+        t.set_frozen(true);
+        assert_eq!(t.expire_stale_holder(since + 60, 60), None);
+        t.set_frozen(false);
+        assert_eq!(t.expire_stale_holder(since + 60, 60).map(|u| u.id().to_owned()),
+                   Some("id0".to_string()));
+        assert!(t.is_holding_id("id1"));
+    }
+
+    #[test]
+    fn test_expire_auto_drop() {
+        let mut t = test_utils::token_with_users(2);
+        let since = t.holder_since().unwrap();
+
+        // Opted out holders are kept however long they hold
+        assert_eq!(t.expire_auto_drop(since + 3600), None);
+        assert!(t.is_holding_id("id0"));
+
+        t.set_auto_drop("id0", Some(60));
+        assert_eq!(t.auto_drop_after("id0"), Some(60));
+        assert_eq!(t.expire_auto_drop(since + 59), None);
+        assert_eq!(t.expire_auto_drop(since + 60).map(|u| u.id().to_owned()),
+                   Some("id0".to_string()));
+        assert!(t.is_holding_id("id1"));
+
+        t.set_auto_drop("id0", None);
+        assert_eq!(t.auto_drop_after("id0"), None);
+    }
+
+    #[test]
+    fn test_purge() {
+        let tokens = Tokens::new();
+        tokens.get_or_create("team", "empty", DEFAULT_TOKEN);
+        let stale = Token::new_with_holder(User::new("id0".to_string(), "name0".to_string()));
+        let since = stale.holder_since().unwrap();
+        *tokens.get_or_create("team", "stale", DEFAULT_TOKEN).write().unwrap() = stale;
+        let mut waiting = Token::new_with_holder(User::new("id1".to_string(), "name1".to_string()));
+        waiting.get(User::new("id2".to_string(), "name2".to_string())).unwrap();
+        *tokens.get_or_create("team", "waiting", DEFAULT_TOKEN).write().unwrap() = waiting;
+
+        // Without a limit only the empty queue goes
+        assert_eq!(tokens.purge(since + 3600, None), (vec![], 1));
+        assert_eq!(tokens.for_channel("team", "stale").len(), 1);
+
+        // The stale holder was alone, so their queue goes too, but the other keeps its waiter
+        let (expired, removed) = tokens.purge(since + 3600, Some(60));
+        let expired: Vec<(&str, &str)> = expired.iter().map(|&((_, ref channel, _), ref u)| (channel.as_str(), u.id())).collect();
+        assert_eq!(expired, vec![("stale", "id0"), ("waiting", "id1")]);
+        assert_eq!(removed, 1);
+        assert_eq!(tokens.for_channel("team", "stale").len(), 0);
+        let waiting = tokens.get_or_create("team", "waiting", DEFAULT_TOKEN);
+        assert!(waiting.read().unwrap().is_holding_id("id2"));
+    }
+
+    #[test]
+    fn test_global_stats() {
+        let tokens = Tokens::new();
+        assert_eq!(tokens.global_stats(0), GlobalStats::default());
+
+        let old = Token::new_with_holder(User::new("id0".to_string(), "name0".to_string()));
+        let since = old.holder_since().unwrap();
+        *tokens.get_or_create("team", "C1", DEFAULT_TOKEN).write().unwrap() = old;
+        let busy = tokens.get_or_create("team", "C2", DEFAULT_TOKEN);
+        let prod = tokens.get_or_create("team", "C2", "prod");
+        tokens.get_or_create("team", "C3", DEFAULT_TOKEN);
+        for i in 1..4 {
+            busy.write().unwrap().get(User::new(format!("id{}", i), format!("name{}", i))).unwrap();
+        }
+        prod.write().unwrap().get(User::new("id4".to_string(), "name4".to_string())).unwrap();
+        busy.write().unwrap().holder_since = Some(since + 10);
+        prod.write().unwrap().holder_since = Some(since + 20);
+
+        let stats = tokens.global_stats(since + 100);
+        assert_eq!(stats.queues, 4);
+        assert_eq!(stats.waiting, 2);
+        assert_eq!(stats.longest_held,
+                   Some((("team".to_string(), "C1".to_string(), DEFAULT_TOKEN.to_string()), 100)));
+        assert_eq!(stats.busiest_channel, Some((("team".to_string(), "C2".to_string()), 4)));
+    }
+
+    #[test]
+    fn test_import_snapshot() {
+        let tokens = Tokens::new();
+        *tokens.get_or_create("team", "channel", DEFAULT_TOKEN).write().unwrap() = test_utils::token_with_users(3);
+        *tokens.get_or_create("team", "channel", "prod").write().unwrap() = test_utils::token_with_users(1);
+        tokens.get_or_create("team", "other", DEFAULT_TOKEN).write().unwrap().set_frozen(true);
+        let snapshots = tokens.snapshot();
+        assert_eq!(snapshots.len(), 3);
+
+        let restored = Tokens::new();
+        restored.get_or_create("team", "stale", DEFAULT_TOKEN);
+        assert_eq!(restored.import_snapshot(tokens.snapshot()), Ok(3));
+        assert_eq!(restored.snapshot(), snapshots);
+        assert!(restored.for_channel("team", "stale").is_empty());
+
+        // A duplicate leaves what was there alone
+        let mut duplicated = tokens.snapshot();
+        duplicated.extend(tokens.snapshot_channel("team", "other"));
+        assert_eq!(restored.import_snapshot(duplicated),
+                   Err("`team/other/default` appears more than once".to_string()));
+        assert_eq!(restored.snapshot(), snapshots);
+    }
+
+    #[test]
+    fn test_get_snapshot_json() {
+        let tokens = Tokens::new();
+        let demo = tokens.get_or_create("team", "channel", "demo");
+        demo.write().unwrap().get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+
+        let first = tokens.get_snapshot_json().unwrap();
+        assert!(first.contains("name0"));
+        // Nothing changed, so the cached JSON is reused
+        let cached = tokens.1.lock().unwrap().as_ref().map(|&(_, ref json)| json.as_ptr()).unwrap();
+        assert_eq!(tokens.get_snapshot_json().unwrap(), first);
+        assert_eq!(tokens.1.lock().unwrap().as_ref().map(|&(_, ref json)| json.as_ptr()).unwrap(), cached);
+
+        demo.write().unwrap().get(User::new("id1".to_string(), "name1".to_string())).unwrap();
+        let second = tokens.get_snapshot_json().unwrap();
+        assert!(second.contains("name1"));
+
+        tokens.get_or_create("team", "other", DEFAULT_TOKEN);
+        assert!(tokens.get_snapshot_json().unwrap().contains("\"other\""));
+    }
+
+    #[test]
+    fn test_snapshot_channel() {
+        let tokens = Tokens::new();
+        let demo = tokens.get_or_create("team", "channel", "demo");
+        demo.write().unwrap().get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        tokens.get_or_create("team", "channel", DEFAULT_TOKEN);
+        tokens.get_or_create("team", "other", "elsewhere");
+
+        let snapshots = tokens.snapshot_channel("team", "channel");
+        let names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["default", "demo"]);
+        assert_eq!(snapshots[1].token, *demo.read().unwrap());
+
+        let json = serde_json::to_string(&snapshots).unwrap();
+        assert!(json.contains("\"demo\""));
+        assert!(!json.contains("elsewhere"));
+    }
+
+    #[test]
+    fn test_tokens_rename() {
+        let tokens = Tokens::new();
+        let demo = tokens.get_or_create("team", "channel", "demo");
+        demo.write().unwrap().get(User::new("id0".to_string(), "name0".to_string())).unwrap();
+        tokens.get_or_create("team", "channel", "taken");
+
+        assert!(tokens.rename("team", "channel", "demo", "demo-laptop").is_ok());
+        let names: Vec<TokenName> = tokens.for_channel("team", "channel").into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec!["demo-laptop", "taken"]);
+        let renamed = tokens.get_or_create("team", "channel", "demo-laptop");
+        assert_eq!(renamed.read().unwrap().list_user_name(), vec!["name0"]);
+
+        assert_eq!(tokens.rename("team", "channel", "demo", "other"),
+                   Err("There is no token with that name!"));
+        assert_eq!(tokens.rename("team", "channel", "demo-laptop", "taken"),
+                   Err("A token with that name already exists!"));
+    }
+
+    #[test]
+    fn test_tokens_limit() {
+        let tokens = Tokens::new();
+        assert!(tokens.get_or_create_within("team", "channel", "a", Some(2)).is_ok());
+        assert!(tokens.get_or_create_within("team", "channel", "b", Some(2)).is_ok());
+        assert_eq!(tokens.get_or_create_within("team", "channel", "c", Some(2)).err(),
+                   Some(vec!["a".to_string(), "b".to_string()]));
+
+        // Existing and default tokens are still available, as are other channels
+        assert!(tokens.get_or_create_within("team", "channel", "a", Some(2)).is_ok());
+        assert!(tokens.get_or_create_within("team", "channel", DEFAULT_TOKEN, Some(2)).is_ok());
+        assert!(tokens.get_or_create_within("team", "other", "c", Some(2)).is_ok());
+    }
+
+    #[test]
+    fn test_is_holding() {
+        let t = test_utils::token_with_users(4);
+        assert!(t.is_holding(&test_utils::user(0)));
+        assert!(!t.is_holding(&test_utils::user(1)));
+    }
+
+    #[test]
+    fn test_is_holding_id() {
+        assert!(!Token::new().is_holding_id("id0"));
+        let t = test_utils::token_with_users(2);
 
-        assert!(t.is_holding(&u0))
+        assert!(t.is_holding_id("id0"));
+        assert!(!t.is_holding_id("id1"));
+        assert!(!t.is_holding_id("name0"));
     }
 }